@@ -1,12 +1,61 @@
+use std::path::PathBuf;
+
+use crate::{keymap::KeymapSpec, output::OutputKind};
+
+/// The args for 'daemon start'
+#[derive(clap::Args)]
+pub struct StartArgs {
+    /// The eww config dir to use
+    #[arg(long)]
+    pub eww_config: Option<PathBuf>,
+    /// The eww binary to use
+    #[arg(long)]
+    pub eww_bin: Option<PathBuf>,
+    /// The word frequency dictionary used for suggestions
+    #[arg(long)]
+    pub dictionary: Option<PathBuf>,
+    /// How long to wait in ms after input stops before hiding the ui
+    #[arg(long, default_value_t = 250)]
+    pub hide_delay: u64,
+    /// How often in seconds to try to get the input method back from another IME
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub input_method_retry: Option<u64>,
+    /// The name of a seat to send keys to, this can be given more than once.
+    /// The input method and keymap come from the first.
+    /// Defaults to the seats in `$XDG_CONFIG_HOME/wl_keys/seats`, or the first seat
+    #[arg(long)]
+    pub seat: Vec<String>,
+    /// The keymap to use if the seat has no keyboard,
+    /// either "default", an xkb file, a layout like "de",
+    /// or names like "layout=us;variant=dvorak;options=caps:escape"
+    #[arg(long, default_value = "default")]
+    pub fallback_keymap: KeymapSpec,
+    /// The keymap to use instead of the real keyboard's,
+    /// in the same format as --fallback-keymap
+    #[arg(long)]
+    pub keymap: Option<KeymapSpec>,
+    /// Where to send the keys,
+    /// "uinput" is for compositors without the virtual keyboard protocol
+    #[arg(long, value_enum, default_value_t)]
+    pub output: OutputKind,
+}
+
 /// The 'daemon' subcmd
 #[derive(clap::Subcommand)]
 pub enum DaemonCmd {
     /// Run the daemon
-    Start,
+    Start(Box<StartArgs>),
     /// Stop the daemon if it's currently running
     Stop,
     /// List the protocols supported by the wm
     Protocols,
+    /// Check the compositor and config for problems,
+    /// this takes the same args as start
+    Doctor(Box<StartArgs>),
+    /// Print the current state of the daemon
+    Status,
+    /// Print events from the daemon as they happen
+    Events,
 }
 
 /// The 'auto' subcmd
@@ -31,6 +80,97 @@ pub enum UiCmd {
     Close,
     /// Toggle the ui
     Toggle,
+    /// Returns true if the ui is shown
+    Query,
+}
+
+/// The 'preedit' subcmd
+#[derive(clap::Subcommand)]
+pub enum PreeditCmd {
+    /// Show uncommitted text in the focused text field
+    Set {
+        /// The text to show
+        text: String,
+        /// Where the cursor starts as a byte offset, defaults to the end of the text
+        #[arg(long)]
+        cursor_begin: Option<i32>,
+        /// Where the cursor ends as a byte offset, defaults to the cursor start
+        #[arg(long)]
+        cursor_end: Option<i32>,
+    },
+    /// Remove the uncommitted text
+    Clear,
+}
+
+/// The 'surrounding' subcmd
+#[derive(clap::Subcommand)]
+pub enum SurroundingCmd {
+    /// Print the text around the cursor
+    Get,
+    /// Delete text around the cursor
+    Delete {
+        /// The number of bytes to delete before the cursor
+        #[arg(long, default_value_t = 0)]
+        before: u32,
+        /// The number of bytes to delete after the cursor
+        #[arg(long, default_value_t = 0)]
+        after: u32,
+    },
+}
+
+/// The 'suggest' subcmd
+#[derive(clap::Subcommand)]
+pub enum SuggestCmd {
+    /// Print words that complete the one being typed
+    Get {
+        /// The max number of suggestions
+        #[arg(long)]
+        limit: Option<u32>,
+        /// Print the words as a JSON array of `{"index", "word"}` for eww,
+        /// which is empty if no text field is focused
+        #[arg(long)]
+        json: bool,
+    },
+    /// Replace the word being typed with a suggestion
+    Accept {
+        /// The suggested word
+        #[arg(required_unless_present = "index")]
+        word: Option<String>,
+        /// Accept the suggestion at this index from `get` instead
+        #[arg(long, conflicts_with = "word")]
+        index: Option<u32>,
+    },
+}
+
+/// The 'seat' subcmd
+#[derive(clap::Subcommand)]
+pub enum SeatCmd {
+    /// Send keys to these seats,
+    /// the input method and keymap come from the first
+    Set {
+        /// The names of the seats, like "seat0"
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+    /// Go back to just the first seat
+    Reset,
+    /// Print the seats, marking the ones keys are sent to
+    List,
+}
+
+/// The 'keymap' subcmd
+#[derive(clap::Subcommand)]
+pub enum KeymapCmd {
+    /// Use a different keymap to the real keyboard
+    Set {
+        /// Either "default", an xkb file, a layout like "de",
+        /// or names like "layout=us;variant=dvorak;options=caps:escape"
+        keymap: KeymapSpec,
+    },
+    /// Go back to the real keyboard's keymap
+    Reset,
+    /// Print the keymap in use and where it's from
+    Get,
 }
 
 /// The 'mod' subcmd
@@ -83,4 +223,30 @@ pub enum Command {
         /// The key to press
         key: String,
     },
+
+    /// Manage uncommitted text in the focused text field
+    #[command(subcommand)]
+    Preedit(PreeditCmd),
+
+    /// Read and delete the text around the cursor
+    #[command(subcommand)]
+    Surrounding(SurroundingCmd),
+
+    /// Suggest words to complete the one being typed
+    #[command(subcommand)]
+    Suggest(SuggestCmd),
+
+    /// Change the virtual keyboard's keymap
+    #[command(subcommand)]
+    Keymap(KeymapCmd),
+
+    /// Choose the seats keys are sent to
+    #[command(subcommand)]
+    Seat(SeatCmd),
+
+    /// Type text into the focused text field
+    Commit {
+        /// The text to type
+        text: String,
+    },
 }
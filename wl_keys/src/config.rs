@@ -1,2 +1,41 @@
+use std::time::Duration;
+
 /// The address for the grpc service
 pub const ADDRESS: &str = "[::1]:50051";
+
+/// Overrides the location of the eww config.
+pub const EWW_CONFIG_ENV: &str = "WL_KEYS_EWW_CONFIG";
+
+/// Overrides the eww binary used.
+pub const EWW_BIN_ENV: &str = "WL_KEYS_EWW_BIN";
+
+/// Set for eww to the path of `wl_keys`, so its config can run it.
+pub const BIN_ENV: &str = "WL_KEYS_BIN";
+
+/// How long eww has to run a command before it's killed.
+pub const EWW_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where packages should install the eww config.
+pub const SYSTEM_EWW_CONFIG: &str = "/usr/share/wl_keys/eww";
+
+/// The word frequency dictionary used for suggestions,
+/// looked for in `$XDG_DATA_HOME/wl_keys`.
+pub const DICTIONARY_FILE: &str = "words.txt";
+
+/// Where the words learned from what the user types are saved,
+/// in `$XDG_DATA_HOME/wl_keys`.
+pub const LEARNED_FILE: &str = "learned";
+
+/// The seats to send keys to, one name on each line,
+/// looked for in `$XDG_CONFIG_HOME/wl_keys`.
+pub const SEATS_FILE: &str = "seats";
+
+/// How many suggestions to give if the client doesn't say.
+pub const DEFAULT_SUGGESTIONS: u32 = 3;
+
+/// How long to wait before the first try at reconnecting to the compositor,
+/// this doubles after each failure.
+pub const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+
+/// The longest to wait between tries at reconnecting to the compositor.
+pub const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
@@ -0,0 +1,169 @@
+use crate::proto::{self, ContentHint, ContentPurpose};
+
+/// The hints that can be set in a `ContentType`.
+const HINTS: [ContentHint; 10] = [
+    ContentHint::Completion,
+    ContentHint::Spellcheck,
+    ContentHint::AutoCapitalization,
+    ContentHint::Lowercase,
+    ContentHint::Uppercase,
+    ContentHint::Titlecase,
+    ContentHint::HiddenText,
+    ContentHint::SensitiveData,
+    ContentHint::Latin,
+    ContentHint::Multiline,
+];
+
+/// What kind of text the focused text field wants,
+/// as sent by the compositor through the input method.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct ContentType {
+    /// A bitmask of `ContentHint`
+    pub hint: u32,
+    /// What the text is for
+    pub purpose: ContentPurpose,
+}
+
+impl ContentType {
+    /// Create from the raw wayland values,
+    /// an unknown purpose is treated as normal.
+    #[must_use]
+    pub fn new(hint: u32, purpose: u32) -> Self {
+        Self {
+            hint,
+            purpose: i32::try_from(purpose)
+                .ok()
+                .and_then(|purpose| ContentPurpose::try_from(purpose).ok())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether the given hint is set
+    #[must_use]
+    pub const fn has_hint(&self, hint: ContentHint) -> bool {
+        self.hint & hint as u32 != 0
+    }
+
+    /// All the hints that are set
+    pub fn hints(&self) -> impl Iterator<Item = ContentHint> + '_ {
+        HINTS.into_iter().filter(|&hint| self.has_hint(hint))
+    }
+
+    /// The layer the ui should show for this content
+    #[must_use]
+    pub const fn layer(&self) -> Layer {
+        match self.purpose {
+            ContentPurpose::Digits
+            | ContentPurpose::Number
+            | ContentPurpose::Phone
+            | ContentPurpose::Pin => Layer::Numeric,
+            _ => Layer::Default,
+        }
+    }
+
+    /// Whether word suggestions should be shown,
+    /// these shouldn't learn from or leak passwords.
+    #[must_use]
+    pub const fn suggestions(&self) -> bool {
+        !matches!(
+            self.purpose,
+            ContentPurpose::Password | ContentPurpose::Pin | ContentPurpose::Terminal
+        ) && !self.has_hint(ContentHint::HiddenText)
+            && !self.has_hint(ContentHint::SensitiveData)
+    }
+}
+
+impl From<ContentType> for proto::ContentType {
+    fn from(content_type: ContentType) -> Self {
+        Self {
+            hint: content_type.hint,
+            purpose: content_type.purpose.into(),
+        }
+    }
+}
+
+/// A set of keys the ui can show
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layer {
+    /// The normal qwerty layout
+    Default,
+    /// Just numbers
+    Numeric,
+}
+
+impl Layer {
+    /// The name used for the layer in eww
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Numeric => "numeric",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn purpose(purpose: ContentPurpose) -> ContentType {
+        ContentType::new(0, purpose as u32)
+    }
+
+    #[test]
+    fn numbers_use_the_numeric_layer() {
+        for numeric in [
+            ContentPurpose::Digits,
+            ContentPurpose::Number,
+            ContentPurpose::Phone,
+            ContentPurpose::Pin,
+        ] {
+            assert_eq!(purpose(numeric).layer(), Layer::Numeric);
+        }
+
+        for default in [
+            ContentPurpose::Normal,
+            ContentPurpose::Email,
+            ContentPurpose::Url,
+            ContentPurpose::Password,
+        ] {
+            assert_eq!(purpose(default).layer(), Layer::Default);
+        }
+    }
+
+    #[test]
+    fn passwords_hide_suggestions() {
+        assert!(purpose(ContentPurpose::Normal).suggestions());
+        assert!(purpose(ContentPurpose::Email).suggestions());
+
+        assert!(!purpose(ContentPurpose::Password).suggestions());
+        assert!(!purpose(ContentPurpose::Pin).suggestions());
+        assert!(!purpose(ContentPurpose::Terminal).suggestions());
+    }
+
+    #[test]
+    fn sensitive_hints_hide_suggestions() {
+        for hint in [ContentHint::HiddenText, ContentHint::SensitiveData] {
+            let content_type = ContentType::new(
+                hint as u32 | ContentHint::Spellcheck as u32,
+                ContentPurpose::Normal as u32,
+            );
+            assert!(!content_type.suggestions());
+        }
+
+        let content_type = ContentType::new(ContentHint::Spellcheck as u32, 0);
+        assert!(content_type.suggestions());
+        assert_eq!(
+            content_type.hints().collect::<Vec<_>>(),
+            [ContentHint::Spellcheck]
+        );
+    }
+
+    #[test]
+    fn unknown_purposes_are_normal() {
+        assert_eq!(
+            ContentType::new(0, u32::MAX).purpose,
+            ContentPurpose::Normal
+        );
+    }
+}
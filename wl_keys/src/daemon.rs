@@ -1,23 +1,35 @@
 use anyhow::{Context, Result};
-use once_cell::sync::Lazy;
 use std::{
-    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
-    time::Duration,
+    io::ErrorKind,
+    os::fd::RawFd,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
 };
-use tokio::sync::mpsc;
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    sync::{mpsc, oneshot},
+};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
 use tonic::{
     transport::{Channel, Server},
     Code, Request, Response, Status,
 };
 
 use crate::{
+    args::StartArgs,
     config,
-    keyboard::Keyboard,
+    keyboard::{Keyboard, KeyboardBackend},
+    keymap::{Keymap, KeymapSpec},
+    predict::{self, Predictor},
     proto::{
         self,
         daemon_client::DaemonClient,
         daemon_server::{Daemon, DaemonServer},
+        Modifier,
     },
+    ui,
 };
 
 /// This allows me to use:
@@ -35,6 +47,57 @@ impl<T, E> InternalError<T> for Result<T, E> {
     }
 }
 
+/// Like `InternalError`, but keeps the error's message
+/// so the client can see what went wrong.
+trait ReportError<T> {
+    /// Map the error to a `Status` with a `Code::Internal` and the error's msg.
+    fn report(self) -> Result<T, Status>;
+}
+
+impl<T> ReportError<T> for Result<T> {
+    fn report(self) -> Result<T, Status> {
+        self.map_err(|err| Status::new(Code::Internal, format!("{err:#}")))
+    }
+}
+
+/// For requests that need a focused text field,
+/// maps `sent` being false to a `Status` with a `Code::FailedPrecondition`.
+fn require_text_field(sent: bool) -> Result<(), Status> {
+    if sent {
+        Ok(())
+    } else {
+        Err(Status::new(
+            Code::FailedPrecondition,
+            "No text field is focused",
+        ))
+    }
+}
+
+/// Auto show needs the input method to know when a text field is focused.
+fn require_input_method(kb: &dyn KeyboardBackend) -> Result<(), Status> {
+    if kb.has_input_method() {
+        Ok(())
+    } else {
+        Err(Status::new(
+            Code::FailedPrecondition,
+            "Auto show needs zwp_input_method_manager_v2, which the compositor doesn't support",
+        ))
+    }
+}
+
+/// While the daemon is reconnecting to the compositor,
+/// requests fail with a `Code::Unavailable` so clients know to try again.
+fn require_connected(kb: &dyn KeyboardBackend) -> Result<(), Status> {
+    if kb.connected() {
+        Ok(())
+    } else {
+        Err(Status::new(
+            Code::Unavailable,
+            "Disconnected from the compositor, reconnecting",
+        ))
+    }
+}
+
 trait ToResponse {
     fn to_res(self) -> Response<Self>
     where
@@ -48,90 +111,328 @@ impl<T> ToResponse for T {}
 
 /// The implementation of the Daemon grpc trait
 pub struct MyDaemon {
-    keyboard: Arc<RwLock<Keyboard>>,
+    ctx: DaemonContext,
+    predictor: Mutex<Predictor>,
     quit_tx: mpsc::Sender<()>,
 }
 
 impl MyDaemon {
-    /// Create a new `MyDaemon`, passing in the ref to the keyboard and the quit sender.
-    pub fn new(keyboard: Arc<RwLock<Keyboard>>, quit_tx: mpsc::Sender<()>) -> Result<Self> {
-        Ok(Self { keyboard, quit_tx })
+    /// Create a new `MyDaemon`, passing in the context with the keyboard,
+    /// the word predictor, and the quit sender.
+    #[must_use]
+    pub const fn new(ctx: DaemonContext, predictor: Predictor, quit_tx: mpsc::Sender<()>) -> Self {
+        Self {
+            ctx,
+            predictor: Mutex::new(predictor),
+            quit_tx,
+        }
+    }
+
+    fn predictor(&self) -> Result<MutexGuard<'_, Predictor>, Status> {
+        self.predictor.lock().internal("Mutex poisoned")
     }
 
-    fn kb_read(&self) -> Result<RwLockReadGuard<Keyboard>, Status> {
-        self.keyboard.read().internal("RwLock poisoned")
+    /// Run `job` on the wayland thread, failing while disconnected.
+    ///
+    /// The whole job runs before the next request's,
+    /// so a request's key events are never mixed with another's.
+    async fn kb<T: Send + 'static>(
+        &self,
+        job: impl FnOnce(&mut dyn KeyboardBackend) -> Result<T, Status> + Send + 'static,
+    ) -> Result<T, Status> {
+        self.ctx
+            .run(|kb| {
+                require_connected(kb)?;
+                job(kb)
+            })
+            .await
+            .report()?
     }
+}
+
+/// The word being typed in the focused text field,
+/// or `None` if suggestions aren't allowed there.
+fn partial_word(kb: &dyn KeyboardBackend) -> Result<Option<String>, Status> {
+    require_text_field(kb.active())?;
 
-    fn kb_write(&self) -> Result<RwLockWriteGuard<Keyboard>, Status> {
-        self.keyboard.write().internal("RwLock poisoned")
+    if !kb.content_type().suggestions() {
+        return Ok(None);
     }
+
+    let partial = kb.surrounding_text().map_or("", |surrounding_text| {
+        predict::partial_word(&surrounding_text.text, surrounding_text.cursor as usize)
+    });
+
+    Ok(Some(partial.to_string()))
+}
+
+/// Press and release a key.
+///
+/// These are sent back to back, the output gives the release a later time
+/// so the compositor still sees them in order, without stalling the wayland thread.
+fn tap(kb: &mut dyn KeyboardBackend, key: u32) -> Result<(), Status> {
+    kb.key(key, true).internal("Wayland request failed")?;
+    kb.key(key, false).internal("Wayland request failed")?;
+
+    Ok(())
 }
 
 #[tonic::async_trait]
 impl Daemon for MyDaemon {
+    type SubscribeStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
     async fn send_key(&self, req: Request<proto::Key>) -> Result<Response<()>, Status> {
         let key = req.get_ref().key;
 
-        self.kb_read()?
-            .key(key, true)
-            .internal("Wayland request failed")?;
+        self.kb(move |kb| {
+            tap(kb, key)?;
+            kb.mod_release_all().internal("Wayland request failed")
+        })
+        .await?;
+
+        Ok(().to_res())
+    }
+
+    async fn commit_string(&self, req: Request<proto::Text>) -> Result<Response<()>, Status> {
+        let text = req.into_inner().text;
+
+        let learn = self
+            .kb({
+                let text = text.clone();
+                move |kb| {
+                    if kb.commit_string(&text).report()? {
+                        return Ok(kb.content_type().suggestions());
+                    }
+
+                    // With no text field focused, fall back to key events
+                    let keys = kb
+                        .text_to_keys(&text)
+                        .map_err(|err| Status::new(Code::InvalidArgument, format!("{err:#}")))?;
 
-        tokio::time::sleep(Duration::from_millis(10)).await;
+                    // The user's modifiers shouldn't change what's typed,
+                    // but they're still latched afterwards
+                    let latched = kb.mods_pressed();
+                    kb.mod_release_all().internal("Wayland request failed")?;
 
-        self.kb_read()?
-            .key(key, false)
-            .internal("Wayland request failed")?;
+                    for (key, shift) in keys {
+                        if shift != kb.mod_query(Modifier::Shift) {
+                            kb.mod_toggle(Modifier::Shift)
+                                .internal("Wayland request failed")?;
+                        }
 
-        self.kb_write()?
-            .mod_release_all()
-            .internal("Wayland request failed")?;
+                        tap(kb, key)?;
+                    }
+
+                    kb.mod_release_all().internal("Wayland request failed")?;
+                    for modifier in latched {
+                        kb.mod_press(modifier).internal("Wayland request failed")?;
+                    }
+
+                    Ok(false)
+                }
+            })
+            .await?;
+
+        if learn {
+            self.predictor()?.learn(&text);
+        }
+
+        Ok(().to_res())
+    }
+
+    async fn set_preedit(&self, req: Request<proto::Preedit>) -> Result<Response<()>, Status> {
+        let proto::Preedit {
+            text,
+            cursor_begin,
+            cursor_end,
+        } = req.into_inner();
+
+        let is_valid =
+            |cursor: i32| usize::try_from(cursor).is_ok_and(|cursor| text.is_char_boundary(cursor));
+        let hidden = cursor_begin == -1 && cursor_end == -1;
+        if !(hidden || is_valid(cursor_begin) && is_valid(cursor_end)) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "The cursor must be on a char boundary in the text",
+            ));
+        }
+
+        self.kb(move |kb| {
+            require_text_field(kb.set_preedit(&text, cursor_begin, cursor_end).report()?)
+        })
+        .await?;
+
+        Ok(().to_res())
+    }
+
+    async fn clear_preedit(&self, _: Request<()>) -> Result<Response<()>, Status> {
+        self.kb(|kb| require_text_field(kb.clear_preedit().report()?))
+            .await?;
+
+        Ok(().to_res())
+    }
+
+    async fn get_surrounding_text(
+        &self,
+        _: Request<()>,
+    ) -> Result<Response<proto::SurroundingText>, Status> {
+        let surrounding_text = self
+            .kb(|kb| {
+                require_text_field(kb.active())?;
+                Ok(kb.surrounding_text().cloned().unwrap_or_default())
+            })
+            .await?;
+
+        Ok(proto::SurroundingText::from(surrounding_text).to_res())
+    }
+
+    async fn delete_surrounding_text(
+        &self,
+        req: Request<proto::DeleteSurrounding>,
+    ) -> Result<Response<()>, Status> {
+        let proto::DeleteSurrounding {
+            before_length,
+            after_length,
+        } = *req.get_ref();
+
+        self.kb(move |kb| {
+            require_text_field(
+                kb.delete_surrounding_text(before_length, after_length)
+                    .report()?,
+            )
+        })
+        .await?;
+
+        Ok(().to_res())
+    }
+
+    async fn get_suggestions(
+        &self,
+        req: Request<proto::SuggestionsRequest>,
+    ) -> Result<Response<proto::Suggestions>, Status> {
+        let limit = match req.get_ref().limit {
+            0 => config::DEFAULT_SUGGESTIONS,
+            limit => limit,
+        };
+
+        let Some(partial) = self.kb(|kb| partial_word(kb)).await? else {
+            return Ok(proto::Suggestions::default().to_res());
+        };
+
+        let words = self.predictor()?.suggest(&partial, limit as usize);
+        Ok(proto::Suggestions { partial, words }.to_res())
+    }
+
+    async fn accept_suggestion(&self, req: Request<proto::Text>) -> Result<Response<()>, Status> {
+        let word = req.into_inner().text;
+        let replacement = format!("{word} ");
+
+        // The partial word is replaced in the same job it's read in,
+        // so the text can't change in between
+        self.kb(move |kb| {
+            let Some(partial) = partial_word(kb)? else {
+                return Err(Status::new(
+                    Code::FailedPrecondition,
+                    "Suggestions aren't allowed in this text field",
+                ));
+            };
+
+            require_text_field(
+                kb.replace_before_cursor(partial.len() as u32, &replacement)
+                    .report()?,
+            )
+        })
+        .await?;
+
+        self.predictor()?.learn(&word);
 
         Ok(().to_res())
     }
 
     async fn auto_enable(&self, _: Request<()>) -> Result<Response<()>, Status> {
-        self.kb_write()?.auto_enable();
+        self.kb(|kb| {
+            require_input_method(kb)?;
+            kb.auto_enable();
+            Ok(())
+        })
+        .await?;
         Ok(().to_res())
     }
 
     async fn auto_disable(&self, _: Request<()>) -> Result<Response<()>, Status> {
-        self.kb_write()?.auto_disable();
+        self.kb(|kb| {
+            kb.auto_disable();
+            Ok(())
+        })
+        .await?;
         Ok(().to_res())
     }
 
     async fn auto_toggle(&self, _: Request<()>) -> Result<Response<()>, Status> {
-        self.kb_write()?.auto_toggle();
+        self.kb(|kb| {
+            if !kb.auto_query() {
+                require_input_method(kb)?;
+            }
+            kb.auto_toggle();
+            Ok(())
+        })
+        .await?;
         Ok(().to_res())
     }
 
     async fn auto_query(&self, _: Request<()>) -> Result<Response<proto::AutoStatus>, Status> {
         Ok(proto::AutoStatus {
-            enabled: self.kb_read()?.auto_query(),
+            enabled: self.kb(|kb| Ok(kb.auto_query())).await?,
+        }
+        .to_res())
+    }
+
+    async fn ui_open(&self, _: Request<()>) -> Result<Response<()>, Status> {
+        // eww is waited on here, so it doesn't hold up the wayland thread
+        let pending = self.kb(|kb| Ok(kb.ui_open())).await?;
+        pending.wait().await.report()?;
+        Ok(().to_res())
+    }
+
+    async fn ui_close(&self, _: Request<()>) -> Result<Response<()>, Status> {
+        // eww is waited on here, so it doesn't hold up the wayland thread
+        let pending = self.kb(|kb| Ok(kb.ui_close())).await?;
+        pending.wait().await.report()?;
+        Ok(().to_res())
+    }
+
+    async fn ui_toggle(&self, _: Request<()>) -> Result<Response<()>, Status> {
+        // eww is waited on here, so it doesn't hold up the wayland thread
+        let pending = self.kb(|kb| Ok(kb.ui_toggle())).await?;
+        pending.wait().await.report()?;
+        Ok(().to_res())
+    }
+
+    async fn ui_query(&self, _: Request<()>) -> Result<Response<proto::UiStatus>, Status> {
+        Ok(proto::UiStatus {
+            open: self.kb(|kb| Ok(kb.ui_query())).await?,
         }
         .to_res())
     }
 
     async fn mod_press(&self, req: Request<proto::ModMsg>) -> Result<Response<()>, Status> {
         let modifier = req.get_ref().modifier();
-        self.kb_write()?
-            .mod_press(modifier)
-            .internal("Wayland request failed")?;
+        self.kb(move |kb| kb.mod_press(modifier).internal("Wayland request failed"))
+            .await?;
         Ok(().to_res())
     }
 
     async fn mod_release(&self, req: Request<proto::ModMsg>) -> Result<Response<()>, Status> {
         let modifier = req.get_ref().modifier();
-        self.kb_write()?
-            .mod_release(modifier)
-            .internal("Wayland request failed")?;
+        self.kb(move |kb| kb.mod_release(modifier).internal("Wayland request failed"))
+            .await?;
         Ok(().to_res())
     }
 
     async fn mod_toggle(&self, req: Request<proto::ModMsg>) -> Result<Response<()>, Status> {
         let modifier = req.get_ref().modifier();
-        self.kb_write()?
-            .mod_toggle(modifier)
-            .internal("Wayland request failed")?;
+        self.kb(move |kb| kb.mod_toggle(modifier).internal("Wayland request failed"))
+            .await?;
         Ok(().to_res())
     }
 
@@ -140,30 +441,359 @@ impl Daemon for MyDaemon {
         req: Request<proto::ModMsg>,
     ) -> Result<Response<proto::ModStatus>, Status> {
         let modifier = req.get_ref().modifier();
-        let pressed = self.kb_read()?.mod_query(modifier);
+        let pressed = self.kb(move |kb| Ok(kb.mod_query(modifier))).await?;
         Ok(proto::ModStatus { pressed }.to_res())
     }
 
+    async fn set_keymap(
+        &self,
+        req: Request<proto::KeymapSpec>,
+    ) -> Result<Response<proto::KeymapStatus>, Status> {
+        // Compiling can be slow, so it's done here to not hold up the wayland thread
+        let keymap = KeymapSpec::try_from(req.into_inner())
+            .and_then(|spec| Keymap::from_text(&spec.text()?))
+            .map_err(|err| Status::new(Code::InvalidArgument, format!("{err:#}")))?;
+
+        let status = self
+            .kb(move |kb| {
+                kb.set_keymap(&keymap).report()?;
+                Ok(kb.keymap_status())
+            })
+            .await?;
+        Ok(status.to_res())
+    }
+
+    async fn reset_keymap(&self, _: Request<()>) -> Result<Response<proto::KeymapStatus>, Status> {
+        let status = self
+            .kb(|kb| {
+                kb.reset_keymap().report()?;
+                Ok(kb.keymap_status())
+            })
+            .await?;
+        Ok(status.to_res())
+    }
+
+    async fn get_keymap(&self, _: Request<()>) -> Result<Response<proto::KeymapStatus>, Status> {
+        Ok(self.kb(|kb| Ok(kb.keymap_status())).await?.to_res())
+    }
+
+    async fn set_seats(
+        &self,
+        req: Request<proto::Seats>,
+    ) -> Result<Response<proto::Seats>, Status> {
+        let names = req.into_inner().names;
+
+        let seats = self
+            .kb(move |kb| {
+                kb.check_seats(&names)
+                    .map_err(|err| Status::new(Code::InvalidArgument, format!("{err:#}")))?;
+                kb.set_seats(&names).report()?;
+                Ok(proto::Seats {
+                    names: kb.active_seat_names(),
+                })
+            })
+            .await?;
+        Ok(seats.to_res())
+    }
+
     async fn stop(&self, _: Request<()>) -> Result<Response<()>, Status> {
         self.quit_tx.send(()).await.internal("Quit signal closed")?;
         Ok(().to_res())
     }
 
     async fn get_protocols(&self, _: Request<()>) -> Result<Response<proto::Protocols>, Status> {
-        Ok(proto::Protocols {
-            protocols: self.kb_read()?.protocols(),
+        let protocols = self
+            .kb(|kb| {
+                let protocols = kb.protocols();
+
+                // Older clients only read the interface names
+                #[allow(deprecated)]
+                Ok(proto::Protocols {
+                    interfaces: protocols
+                        .iter()
+                        .map(|protocol| protocol.interface.clone())
+                        .collect(),
+                    protocols,
+                    missing: kb.missing_capabilities(),
+                })
+            })
+            .await?;
+        Ok(protocols.to_res())
+    }
+
+    async fn get_status(&self, _: Request<()>) -> Result<Response<proto::DaemonStatus>, Status> {
+        let status = self
+            .kb(|kb| {
+                let content_type = kb.content_type();
+                Ok(proto::DaemonStatus {
+                    auto: kb.auto_query(),
+                    ui_open: kb.ui_query(),
+                    mods: kb.mods_pressed().into_iter().map(Into::into).collect(),
+                    active: kb.active(),
+                    content_type: Some(content_type.into()),
+                    layer: content_type.layer().as_str().to_string(),
+                    suggestions: content_type.suggestions(),
+                    missing: kb.missing_capabilities(),
+                    input_method: kb.input_method_state().into(),
+                    keymap: Some(kb.keymap_status()),
+                    seat: kb.seat_name(),
+                    seats: kb.seat_names(),
+                    active_seats: kb.active_seat_names(),
+                })
+            })
+            .await?;
+        Ok(status.to_res())
+    }
+
+    async fn subscribe(&self, _: Request<()>) -> Result<Response<Self::SubscribeStream>, Status> {
+        // Clients can subscribe while disconnected, to hear when it's back
+        let events = BroadcastStream::new(self.ctx.run(|kb| kb.subscribe()).await.report()?)
+            // A client that falls behind just misses some events
+            .filter_map(Result::ok)
+            .map(Ok);
+        Ok((Box::pin(events) as Self::SubscribeStream).to_res())
+    }
+}
+
+/// Work for the wayland thread to do with the keyboard
+type Job = Box<dyn FnOnce(&mut dyn KeyboardBackend) + Send>;
+
+/// What the daemon shares between requests.
+///
+/// The keyboard lives on its own thread, which runs jobs sent to it in order
+/// between handling wayland events.
+/// This is cheap to clone, all the clones send to the same thread.
+#[derive(Clone)]
+pub struct DaemonContext {
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl DaemonContext {
+    /// Connect to the compositor and set up the keyboard from the args
+    pub fn new(args: &StartArgs) -> Result<Self> {
+        let mut keyboard =
+            Keyboard::new(args.output).context("Failed to connect to the compositor")?;
+
+        #[cfg(feature = "uinput")]
+        if args.output == crate::output::OutputKind::Uinput {
+            eprintln!(
+                "Warning: the compositor types keys from uinput with its own keymap, \
+                so wl_keys' keymap is only used to choose which keys to send"
+            );
+        }
+
+        let seats = chosen_seats(args)?;
+        if !seats.is_empty() {
+            // They might not have been added yet, like a seat for a device plugged in later
+            if let Err(err) = keyboard.check_seats(&seats) {
+                eprintln!("Warning: {err:#}, it will be used once it's added");
+            }
+            keyboard.set_seats(&seats)?;
+        }
+        keyboard.set_hide_delay(Duration::from_millis(args.hide_delay));
+        keyboard.set_input_method_retry(args.input_method_retry.map(Duration::from_secs));
+        keyboard.set_fallback_keymap(args.fallback_keymap.clone())?;
+        if let Some(spec) = &args.keymap {
+            keyboard.set_keymap(&Keymap::from_text(&spec.text()?)?)?;
+        }
+
+        Self::with_keyboard(keyboard)
+    }
+
+    /// Start the wayland thread with a keyboard that's already set up,
+    /// like one connected to a test compositor
+    pub fn with_keyboard(keyboard: Keyboard) -> Result<Self> {
+        Self::spawn(move |jobs| wayland_thread(keyboard, jobs))
+    }
+
+    /// Start a thread for a keyboard that has no wayland events to handle,
+    /// like a fake one in tests
+    pub fn with_backend(mut backend: impl KeyboardBackend + 'static) -> Result<Self> {
+        Self::spawn(move |mut jobs| {
+            while let Some(job) = jobs.blocking_recv() {
+                job(&mut backend);
+            }
+            Ok(())
+        })
+    }
+
+    /// Start the thread that owns the keyboard,
+    /// `run` gets the jobs and returns once they stop coming
+    fn spawn(
+        run: impl FnOnce(mpsc::UnboundedReceiver<Job>) -> Result<()> + Send + 'static,
+    ) -> Result<Self> {
+        let (jobs_tx, jobs_rx) = mpsc::unbounded_channel();
+
+        std::thread::Builder::new()
+            .name("wayland".to_string())
+            .spawn(move || {
+                if let Err(err) = run(jobs_rx) {
+                    eprintln!("The wayland thread stopped: {err:#}");
+                }
+            })?;
+
+        Ok(Self { jobs: jobs_tx })
+    }
+
+    /// Run `job` on the wayland thread once the jobs sent before it are done,
+    /// and get what it returns
+    pub async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce(&mut dyn KeyboardBackend) -> T + Send + 'static,
+    ) -> Result<T> {
+        let (tx, rx) = oneshot::channel();
+
+        self.jobs
+            .send(Box::new(move |keyboard| {
+                // The request may have been cancelled
+                let _ = tx.send(job(keyboard));
+            }))
+            // Have to do this because the SendError is not Sync
+            .ok()
+            .context("The wayland thread stopped")?;
+
+        rx.await.context("The wayland thread stopped")
+    }
+}
+
+/// Own the keyboard until every `DaemonContext` is dropped
+fn wayland_thread(keyboard: Keyboard, jobs: mpsc::UnboundedReceiver<Job>) -> Result<()> {
+    // This thread has its own runtime to wait on the socket and timers,
+    // so the blocking wayland calls never stall the grpc server
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(wayland_loop(keyboard, jobs))
+}
+
+/// Handle wayland events as they arrive, run jobs as they're sent,
+/// and run anything scheduled,
+/// reconnecting to the compositor if the connection is lost.
+async fn wayland_loop(
+    mut keyboard: Keyboard,
+    mut jobs: mpsc::UnboundedReceiver<Job>,
+) -> Result<()> {
+    // How long to wait before trying to reconnect
+    let mut backoff = config::RECONNECT_MIN_DELAY;
+    let mut reconnect_at = Instant::now();
+    // This is replaced on reconnect, as the socket changes
+    let mut socket: Option<AsyncFd<RawFd>> = None;
+
+    loop {
+        if keyboard.connected() {
+            match keyboard.dispatch() {
+                Ok(()) => keyboard.tick(),
+                Err(err) => eprintln!("Lost the connection to the compositor: {err:#}"),
+            }
+        }
+
+        let wake_at = if keyboard.connected() {
+            // More events were queued, so they need dispatching first
+            let Some(fd) = keyboard.fd() else {
+                continue;
+            };
+
+            if socket.as_ref().map(AsyncFd::get_ref) != Some(&fd) {
+                socket = Some(AsyncFd::with_interest(fd, Interest::READABLE)?);
+            }
+
+            keyboard.next_tick()
+        } else {
+            socket = None;
+
+            // Jobs still run while waiting, and fail as unavailable
+            if Instant::now() >= reconnect_at {
+                match keyboard.reconnect() {
+                    Ok(()) => {
+                        eprintln!("Reconnected to the compositor");
+                        backoff = config::RECONNECT_MIN_DELAY;
+                        continue;
+                    }
+                    Err(err) => {
+                        eprintln!(
+                            "Failed to reconnect, trying again in {}ms: {err:#}",
+                            backoff.as_millis()
+                        );
+                        reconnect_at = Instant::now() + backoff;
+                        backoff = (backoff * 2).min(config::RECONNECT_MAX_DELAY);
+                    }
+                }
+            }
+
+            Some(reconnect_at)
+        };
+
+        let readable = async {
+            match &socket {
+                Some(socket) => socket.readable().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let wake = async {
+            match wake_at {
+                Some(at) => tokio::time::sleep_until(at.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            job = jobs.recv() => match job {
+                Some(job) => job(&mut keyboard),
+                // The daemon has stopped
+                None => return Ok(()),
+            },
+            ready = readable => {
+                let mut ready = ready?;
+                if let Err(err) = keyboard.read_events() {
+                    eprintln!("Lost the connection to the compositor: {err:#}");
+                }
+                // That reads until the socket is empty
+                ready.clear_ready();
+            }
+            () = wake => (),
         }
-        .to_res())
     }
 }
 
-// No other way in a static
-#[allow(clippy::unwrap_used)]
-static KEYBOARD: Lazy<Arc<RwLock<Keyboard>>> =
-    Lazy::new(|| Arc::new(RwLock::new(Keyboard::new().unwrap())));
+/// The seats to send keys to, from `--seat` or the seats file
+/// in `$XDG_CONFIG_HOME/wl_keys`, empty for just the first seat.
+///
+/// The file has a seat name on each line, and lines starting with '#' are ignored.
+pub fn chosen_seats(args: &StartArgs) -> Result<Vec<String>> {
+    if !args.seat.is_empty() {
+        return Ok(args.seat.clone());
+    }
+
+    let Some(path) = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("wl_keys").join(config::SEATS_FILE))
+    else {
+        return Ok(Vec::new());
+    };
+
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(ToString::to_string)
+            .collect()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err).with_context(|| format!("Failed to read seats {}", path.display())),
+    }
+}
 
 /// Run the grpc daemon
-pub async fn daemon() -> Result<()> {
+pub async fn daemon(args: &StartArgs) -> Result<()> {
+    // The RPCs still work without the ui, so this doesn't stop the daemon
+    if let Err(err) = ui::init(args) {
+        eprintln!("Warning: {err:#}, the ui can't be shown until this is fixed");
+    }
+    let predictor = Predictor::find(args.dictionary.as_deref())?;
+
     // Stop the daemon if its already running
     if let Ok(mut client) = client().await {
         let _ = client.stop(()).await;
@@ -174,25 +804,10 @@ pub async fn daemon() -> Result<()> {
         quit_rx.recv().await;
     };
 
-    tokio::spawn(async {
-        loop {
-            tokio::time::sleep(Duration::from_millis(15)).await;
-
-            KEYBOARD
-                .write()
-                // Have to do this because the PoisonError is not Sync
-                .ok()
-                .context("RwLock poisoned")?
-                .roundtrip()?;
-        }
-
-        // This avoids having to explicitely type the return value
-        #[allow(unreachable_code)]
-        Result::<()>::Ok(())
-    });
+    let ctx = DaemonContext::new(args)?;
 
     Server::builder()
-        .add_service(DaemonServer::new(MyDaemon::new(KEYBOARD.clone(), quit_tx)?))
+        .add_service(DaemonServer::new(MyDaemon::new(ctx, predictor, quit_tx)))
         .serve_with_shutdown(config::ADDRESS.parse()?, quit_signal)
         .await?;
 
@@ -204,3 +819,813 @@ pub async fn client() -> Result<DaemonClient<Channel>> {
     let addr = format!("https://{}", config::ADDRESS);
     DaemonClient::connect(addr).await.map_err(Into::into)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::{net::TcpListener, sync::broadcast, task::JoinHandle};
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    use super::*;
+    use crate::{content::ContentType, keyboard::SurroundingText, proto::KeymapSource};
+
+    /// What the fake keyboard was asked to send
+    #[derive(Debug, PartialEq, Eq)]
+    enum Sent {
+        Key(u32, bool),
+        /// The modifiers were set to these
+        Mods(Vec<Modifier>),
+        CommitString(String),
+        Preedit(String, i32, i32),
+        Delete(u32, u32),
+        /// The symbols of the new keymap
+        Keymap(String),
+    }
+
+    /// A keyboard that records what it sends instead of using wayland
+    #[allow(clippy::struct_excessive_bools)]
+    struct FakeKeyboard {
+        sent: Arc<Mutex<Vec<Sent>>>,
+        events: broadcast::Sender<proto::Event>,
+        connected: bool,
+        has_input_method: bool,
+        auto: bool,
+        ui_open: bool,
+        mods: Vec<Modifier>,
+        /// Set if a text field is focused
+        surrounding_text: Option<SurroundingText>,
+        content_type: ContentType,
+        keymap: proto::KeymapStatus,
+        /// The seats keys are sent to
+        active_seats: Vec<String>,
+    }
+
+    impl Default for FakeKeyboard {
+        fn default() -> Self {
+            Self {
+                sent: Arc::default(),
+                events: broadcast::channel(16).0,
+                connected: true,
+                has_input_method: true,
+                auto: false,
+                ui_open: false,
+                mods: Vec::new(),
+                surrounding_text: None,
+                content_type: ContentType::default(),
+                keymap: seat_keymap(),
+                active_seats: vec!["seat0".to_string()],
+            }
+        }
+    }
+
+    impl FakeKeyboard {
+        /// A fake with a text field focused, with the cursor at the end of `text`
+        fn focused(text: &str) -> Self {
+            Self {
+                surrounding_text: Some(SurroundingText {
+                    text: text.to_string(),
+                    cursor: text.len() as u32,
+                    anchor: text.len() as u32,
+                }),
+                ..Self::default()
+            }
+        }
+
+        fn send(&self, sent: Sent) {
+            if let Ok(mut all) = self.sent.lock() {
+                all.push(sent);
+            }
+        }
+
+        fn set_mods(&mut self, mods: Vec<Modifier>) {
+            self.mods = mods;
+            self.send(Sent::Mods(self.mods.clone()));
+        }
+
+        /// Only sends if a text field is focused, like the input method
+        fn commit(&self, sent: impl IntoIterator<Item = Sent>) -> bool {
+            if self.surrounding_text.is_some() {
+                sent.into_iter().for_each(|sent| self.send(sent));
+            }
+            self.surrounding_text.is_some()
+        }
+
+        fn set_ui(&mut self, open: bool) {
+            self.ui_open = open;
+            let _ = self.events.send(proto::Event {
+                event: Some(proto::event::Event::Ui(proto::UiStatus { open })),
+            });
+        }
+    }
+
+    fn seat_keymap() -> proto::KeymapStatus {
+        proto::KeymapStatus {
+            symbols: "pc+us+inet(evdev)".to_string(),
+            source: KeymapSource::Seat.into(),
+        }
+    }
+
+    impl KeyboardBackend for FakeKeyboard {
+        fn connected(&self) -> bool {
+            self.connected
+        }
+
+        fn seat_name(&self) -> String {
+            self.active_seats[0].clone()
+        }
+
+        fn seat_names(&self) -> Vec<String> {
+            vec!["seat0".to_string(), "seat1".to_string()]
+        }
+
+        fn active_seat_names(&self) -> Vec<String> {
+            self.active_seats.clone()
+        }
+
+        fn set_seats(&mut self, names: &[String]) -> Result<()> {
+            self.active_seats = if names.is_empty() {
+                vec!["seat0".to_string()]
+            } else {
+                names.to_vec()
+            };
+            Ok(())
+        }
+
+        fn set_keymap(&mut self, keymap: &Keymap) -> Result<()> {
+            let symbols = crate::keymap::symbols(&keymap.contents()?);
+            self.send(Sent::Keymap(symbols.clone()));
+            self.keymap = proto::KeymapStatus {
+                symbols,
+                source: KeymapSource::Custom.into(),
+            };
+            Ok(())
+        }
+
+        fn reset_keymap(&mut self) -> Result<()> {
+            self.keymap = seat_keymap();
+            self.send(Sent::Keymap(self.keymap.symbols.clone()));
+            Ok(())
+        }
+
+        fn key(&mut self, key: u32, pressed: bool) -> Result<()> {
+            self.send(Sent::Key(key, pressed));
+            Ok(())
+        }
+
+        fn text_to_keys(&self, text: &str) -> Result<Vec<(u32, bool)>> {
+            text.chars().map(crate::keycode::char_to_key).collect()
+        }
+
+        fn commit_string(&self, text: &str) -> Result<bool> {
+            Ok(self.commit([Sent::CommitString(text.to_string())]))
+        }
+
+        fn set_preedit(&self, text: &str, cursor_begin: i32, cursor_end: i32) -> Result<bool> {
+            Ok(self.commit([Sent::Preedit(text.to_string(), cursor_begin, cursor_end)]))
+        }
+
+        fn delete_surrounding_text(&self, before_length: u32, after_length: u32) -> Result<bool> {
+            Ok(self.commit([Sent::Delete(before_length, after_length)]))
+        }
+
+        fn replace_before_cursor(&self, length: u32, text: &str) -> Result<bool> {
+            Ok(self.commit([
+                Sent::Delete(length, 0),
+                Sent::CommitString(text.to_string()),
+            ]))
+        }
+
+        fn auto_enable(&mut self) {
+            self.auto = true;
+        }
+
+        fn auto_disable(&mut self) {
+            self.auto = false;
+        }
+
+        fn auto_query(&self) -> bool {
+            self.auto
+        }
+
+        fn ui_open(&mut self) -> ui::Pending {
+            self.set_ui(true);
+            ui::Pending::ready(Ok(()))
+        }
+
+        fn ui_close(&mut self) -> ui::Pending {
+            self.set_ui(false);
+            ui::Pending::ready(Ok(()))
+        }
+
+        fn ui_toggle(&mut self) -> ui::Pending {
+            self.set_ui(!self.ui_open);
+            ui::Pending::ready(Ok(()))
+        }
+
+        fn ui_query(&self) -> bool {
+            self.ui_open
+        }
+
+        fn mod_press(&mut self, modifier: Modifier) -> Result<()> {
+            let mut mods = self.mods.clone();
+            if !mods.contains(&modifier) {
+                mods.push(modifier);
+            }
+            self.set_mods(mods);
+            Ok(())
+        }
+
+        fn mod_release(&mut self, modifier: Modifier) -> Result<()> {
+            let mut mods = self.mods.clone();
+            mods.retain(|&other| other != modifier);
+            self.set_mods(mods);
+            Ok(())
+        }
+
+        fn mod_toggle(&mut self, modifier: Modifier) -> Result<()> {
+            if self.mod_query(modifier) {
+                self.mod_release(modifier)
+            } else {
+                self.mod_press(modifier)
+            }
+        }
+
+        fn mod_query(&self, modifier: Modifier) -> bool {
+            self.mods.contains(&modifier)
+        }
+
+        fn active(&self) -> bool {
+            self.surrounding_text.is_some()
+        }
+
+        fn content_type(&self) -> ContentType {
+            self.content_type
+        }
+
+        fn surrounding_text(&self) -> Option<&SurroundingText> {
+            self.surrounding_text.as_ref()
+        }
+
+        fn subscribe(&self) -> broadcast::Receiver<proto::Event> {
+            self.events.subscribe()
+        }
+
+        fn mod_release_all(&mut self) -> Result<()> {
+            self.set_mods(Vec::new());
+            Ok(())
+        }
+
+        fn has_input_method(&self) -> bool {
+            self.has_input_method
+        }
+
+        fn keymap_status(&self) -> proto::KeymapStatus {
+            self.keymap.clone()
+        }
+
+        fn input_method_state(&self) -> proto::InputMethodState {
+            if self.has_input_method {
+                proto::InputMethodState::Available
+            } else {
+                proto::InputMethodState::Missing
+            }
+        }
+
+        fn missing_capabilities(&self) -> Vec<String> {
+            if self.has_input_method {
+                Vec::new()
+            } else {
+                vec!["auto show and text input need the input method".to_string()]
+            }
+        }
+
+        fn protocols(&self) -> Vec<proto::Protocol> {
+            vec![proto::Protocol {
+                interface: "zwp_virtual_keyboard_manager_v1".to_string(),
+                version: 1,
+                count: 1,
+                bound_version: 1,
+                names: vec![1],
+                used: true,
+            }]
+        }
+    }
+
+    /// The daemon serving a fake keyboard on an ephemeral port
+    struct TestDaemon {
+        client: DaemonClient<Channel>,
+        sent: Arc<Mutex<Vec<Sent>>>,
+        server: JoinHandle<Result<(), tonic::transport::Error>>,
+    }
+
+    impl TestDaemon {
+        async fn start(keyboard: FakeKeyboard) -> Result<Self> {
+            let sent = keyboard.sent.clone();
+            let ctx = DaemonContext::with_backend(keyboard)?;
+
+            let mut predictor = Predictor::default();
+            predictor.learn("hello help");
+
+            let (quit_tx, mut quit_rx) = mpsc::channel::<()>(1);
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+
+            let server = tokio::spawn(
+                Server::builder()
+                    .add_service(DaemonServer::new(MyDaemon::new(ctx, predictor, quit_tx)))
+                    .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async move {
+                        quit_rx.recv().await;
+                    }),
+            );
+
+            let client = DaemonClient::connect(format!("http://{addr}")).await?;
+
+            Ok(Self {
+                client,
+                sent,
+                server,
+            })
+        }
+
+        /// Take everything the keyboard sent since the last call
+        fn sent(&self) -> Vec<Sent> {
+            self.sent
+                .lock()
+                .map(|mut sent| std::mem::take(&mut *sent))
+                .unwrap_or_default()
+        }
+    }
+
+    /// The error code of a response, `None` if it succeeded
+    fn code<T>(res: Result<Response<T>, Status>) -> Option<Code> {
+        res.err().map(|err| err.code())
+    }
+
+    fn modifier(modifier: Modifier) -> proto::ModMsg {
+        proto::ModMsg {
+            modifier: modifier.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_key_releases_the_modifiers() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard::default()).await?;
+
+        daemon.client.mod_press(modifier(Modifier::Ctrl)).await?;
+        daemon.client.send_key(proto::Key { key: 30 }).await?;
+
+        assert_eq!(
+            daemon.sent(),
+            [
+                Sent::Mods(vec![Modifier::Ctrl]),
+                Sent::Key(30, true),
+                Sent::Key(30, false),
+                Sent::Mods(Vec::new()),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn modifiers() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard::default()).await?;
+        let client = &mut daemon.client;
+
+        client.mod_press(modifier(Modifier::Shift)).await?;
+        client.mod_toggle(modifier(Modifier::Alt)).await?;
+        client.mod_release(modifier(Modifier::Shift)).await?;
+        let pressed = |status: Response<proto::ModStatus>| status.into_inner().pressed;
+        assert!(pressed(client.mod_query(modifier(Modifier::Alt)).await?));
+        assert!(!pressed(client.mod_query(modifier(Modifier::Shift)).await?));
+        client.mod_toggle(modifier(Modifier::Alt)).await?;
+
+        assert_eq!(
+            daemon.sent(),
+            [
+                Sent::Mods(vec![Modifier::Shift]),
+                Sent::Mods(vec![Modifier::Shift, Modifier::Alt]),
+                Sent::Mods(vec![Modifier::Alt]),
+                Sent::Mods(Vec::new()),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn commit_string_to_a_text_field() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard::focused("")).await?;
+
+        daemon
+            .client
+            .commit_string(proto::Text {
+                text: "Hi".to_string(),
+            })
+            .await?;
+
+        assert_eq!(daemon.sent(), [Sent::CommitString("Hi".to_string())]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn commit_string_falls_back_to_keys() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard {
+            mods: vec![Modifier::Ctrl],
+            ..FakeKeyboard::default()
+        })
+        .await?;
+
+        daemon
+            .client
+            .commit_string(proto::Text {
+                text: "Hi".to_string(),
+            })
+            .await?;
+
+        // The latched ctrl isn't held while typing, but is latched again after
+        assert_eq!(
+            daemon.sent(),
+            [
+                Sent::Mods(Vec::new()),
+                Sent::Mods(vec![Modifier::Shift]),
+                Sent::Key(35, true),
+                Sent::Key(35, false),
+                Sent::Mods(Vec::new()),
+                Sent::Key(23, true),
+                Sent::Key(23, false),
+                Sent::Mods(Vec::new()),
+                Sent::Mods(vec![Modifier::Ctrl]),
+            ]
+        );
+
+        // Nothing is typed if any char can't be
+        assert_eq!(
+            code(
+                daemon
+                    .client
+                    .commit_string(proto::Text {
+                        text: "aä".to_string(),
+                    })
+                    .await
+            ),
+            Some(Code::InvalidArgument)
+        );
+        assert_eq!(daemon.sent(), []);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn preedit() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard::focused("")).await?;
+        let client = &mut daemon.client;
+
+        client
+            .set_preedit(proto::Preedit {
+                text: "hé".to_string(),
+                cursor_begin: 0,
+                cursor_end: 3,
+            })
+            .await?;
+        client.clear_preedit(()).await?;
+
+        // The cursor is in the middle of é
+        assert_eq!(
+            code(
+                client
+                    .set_preedit(proto::Preedit {
+                        text: "hé".to_string(),
+                        cursor_begin: 2,
+                        cursor_end: 2,
+                    })
+                    .await
+            ),
+            Some(Code::InvalidArgument)
+        );
+
+        assert_eq!(
+            daemon.sent(),
+            [
+                Sent::Preedit("hé".to_string(), 0, 3),
+                Sent::Preedit(String::new(), 0, 0),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn text_requests_need_a_text_field() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard::default()).await?;
+        let client = &mut daemon.client;
+
+        let codes = [
+            code(client.clear_preedit(()).await),
+            code(client.get_surrounding_text(()).await),
+            code(
+                client
+                    .delete_surrounding_text(proto::DeleteSurrounding::default())
+                    .await,
+            ),
+            code(
+                client
+                    .get_suggestions(proto::SuggestionsRequest::default())
+                    .await,
+            ),
+        ];
+
+        assert_eq!(codes, [Some(Code::FailedPrecondition); 4]);
+        assert_eq!(daemon.sent(), []);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn surrounding_text() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard::focused("one two")).await?;
+
+        let surrounding_text = daemon.client.get_surrounding_text(()).await?.into_inner();
+        assert_eq!(
+            surrounding_text,
+            proto::SurroundingText {
+                text: "one two".to_string(),
+                cursor: 7,
+                anchor: 7,
+            }
+        );
+
+        daemon
+            .client
+            .delete_surrounding_text(proto::DeleteSurrounding {
+                before_length: 3,
+                after_length: 0,
+            })
+            .await?;
+        assert_eq!(daemon.sent(), [Sent::Delete(3, 0)]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn suggestions() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard::focused("say hel")).await?;
+
+        let suggestions = daemon
+            .client
+            .get_suggestions(proto::SuggestionsRequest::default())
+            .await?
+            .into_inner();
+        assert_eq!(
+            suggestions,
+            proto::Suggestions {
+                partial: "hel".to_string(),
+                words: vec!["hello".to_string(), "help".to_string()],
+            }
+        );
+
+        daemon
+            .client
+            .accept_suggestion(proto::Text {
+                text: "help".to_string(),
+            })
+            .await?;
+        assert_eq!(
+            daemon.sent(),
+            [Sent::Delete(3, 0), Sent::CommitString("help ".to_string()),]
+        );
+
+        // Accepting it was learnt, so it's suggested first
+        let suggestions = daemon
+            .client
+            .get_suggestions(proto::SuggestionsRequest { limit: 1 })
+            .await?
+            .into_inner();
+        assert_eq!(suggestions.words, ["help"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn password_fields_hide_suggestions() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard {
+            content_type: ContentType::new(0, proto::ContentPurpose::Password as u32),
+            ..FakeKeyboard::focused("hel")
+        })
+        .await?;
+        let client = &mut daemon.client;
+
+        let status = client.get_status(()).await?.into_inner();
+        assert!(!status.suggestions);
+
+        let suggestions = client
+            .get_suggestions(proto::SuggestionsRequest::default())
+            .await?
+            .into_inner();
+        assert_eq!(suggestions, proto::Suggestions::default());
+
+        assert_eq!(
+            code(
+                client
+                    .accept_suggestion(proto::Text {
+                        text: "hello".to_string(),
+                    })
+                    .await
+            ),
+            Some(Code::FailedPrecondition)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auto() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard::default()).await?;
+        let client = &mut daemon.client;
+        let enabled = |status: Response<proto::AutoStatus>| status.into_inner().enabled;
+
+        client.auto_enable(()).await?;
+        assert!(enabled(client.auto_query(()).await?));
+        client.auto_toggle(()).await?;
+        assert!(!enabled(client.auto_query(()).await?));
+        client.auto_toggle(()).await?;
+        client.auto_disable(()).await?;
+        assert!(!enabled(client.auto_query(()).await?));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auto_needs_the_input_method() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard {
+            has_input_method: false,
+            ..FakeKeyboard::default()
+        })
+        .await?;
+        let client = &mut daemon.client;
+
+        assert_eq!(
+            code(client.auto_enable(()).await),
+            Some(Code::FailedPrecondition)
+        );
+        assert_eq!(
+            code(client.auto_toggle(()).await),
+            Some(Code::FailedPrecondition)
+        );
+
+        let protocols = client.get_protocols(()).await?.into_inner();
+        assert_eq!(protocols.missing.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ui_and_subscribe() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard::default()).await?;
+        let client = &mut daemon.client;
+        let mut events = client.subscribe(()).await?.into_inner();
+        let open = |status: Response<proto::UiStatus>| status.into_inner().open;
+
+        client.ui_open(()).await?;
+        assert!(open(client.ui_query(()).await?));
+        client.ui_toggle(()).await?;
+        assert!(!open(client.ui_query(()).await?));
+        client.ui_toggle(()).await?;
+        client.ui_close(()).await?;
+
+        for expected in [true, false, true, false] {
+            let event = events.message().await?.and_then(|event| event.event);
+            assert_eq!(
+                event,
+                Some(proto::event::Event::Ui(proto::UiStatus { open: expected }))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keymap() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard::default()).await?;
+        let client = &mut daemon.client;
+
+        assert_eq!(client.get_keymap(()).await?.into_inner(), seat_keymap());
+
+        let custom = proto::KeymapStatus {
+            symbols: "pc+us+inet(evdev)".to_string(),
+            source: KeymapSource::Custom.into(),
+        };
+        let status = client
+            .set_keymap(proto::KeymapSpec::from(KeymapSpec::Default))
+            .await?
+            .into_inner();
+        assert_eq!(status, custom);
+        assert_eq!(client.get_keymap(()).await?.into_inner(), custom);
+
+        assert_eq!(
+            code(client.set_keymap(proto::KeymapSpec::default()).await),
+            Some(Code::InvalidArgument)
+        );
+
+        assert_eq!(client.reset_keymap(()).await?.into_inner(), seat_keymap());
+
+        assert_eq!(
+            daemon.sent(),
+            [
+                Sent::Keymap("pc+us+inet(evdev)".to_string()),
+                Sent::Keymap("pc+us+inet(evdev)".to_string()),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn status_and_protocols() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard::focused("")).await?;
+        let client = &mut daemon.client;
+
+        client.mod_press(modifier(Modifier::Cmd)).await?;
+        client.auto_enable(()).await?;
+
+        let status = client.get_status(()).await?.into_inner();
+        assert!(status.auto);
+        assert!(!status.ui_open);
+        assert!(status.active);
+        assert!(status.suggestions);
+        assert_eq!(status.mods, [i32::from(Modifier::Cmd)]);
+        assert_eq!(status.input_method(), proto::InputMethodState::Available);
+        assert_eq!(status.keymap, Some(seat_keymap()));
+        assert_eq!(status.seat, "seat0");
+        assert_eq!(status.seats, ["seat0", "seat1"]);
+        assert_eq!(status.active_seats, ["seat0"]);
+
+        let protocols = client.get_protocols(()).await?.into_inner();
+        assert!(protocols.missing.is_empty());
+        assert_eq!(protocols.protocols.len(), 1);
+        // Still given to older clients
+        #[allow(deprecated)]
+        let interfaces = protocols.interfaces;
+        assert_eq!(interfaces, [protocols.protocols[0].interface.clone()]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn seats() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard::default()).await?;
+        let client = &mut daemon.client;
+
+        let names = |names: &[&str]| proto::Seats {
+            names: names.iter().map(ToString::to_string).collect(),
+        };
+
+        let active = client.set_seats(names(&["seat0", "seat1"])).await?;
+        assert_eq!(active.into_inner().names, ["seat0", "seat1"]);
+        let status = client.get_status(()).await?.into_inner();
+        assert_eq!(status.active_seats, ["seat0", "seat1"]);
+
+        assert_eq!(
+            code(client.set_seats(names(&["seat1", "seat2"])).await),
+            Some(Code::InvalidArgument)
+        );
+        let status = client.get_status(()).await?.into_inner();
+        assert_eq!(status.active_seats, ["seat0", "seat1"]);
+
+        let active = client.set_seats(names(&[])).await?;
+        assert_eq!(active.into_inner().names, ["seat0"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn disconnected_is_unavailable() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard {
+            connected: false,
+            ..FakeKeyboard::default()
+        })
+        .await?;
+
+        assert_eq!(
+            code(daemon.client.send_key(proto::Key { key: 30 }).await),
+            Some(Code::Unavailable)
+        );
+        assert_eq!(daemon.sent(), []);
+
+        // Clients can still subscribe, to hear when it's back
+        daemon.client.subscribe(()).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop() -> Result<()> {
+        let mut daemon = TestDaemon::start(FakeKeyboard::default()).await?;
+
+        daemon.client.stop(()).await?;
+        daemon.server.await??;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use anyhow::{bail, Result};
+use std::fmt::Display;
+use wayland_client::Connection;
+
+use crate::{
+    args::StartArgs,
+    daemon::{self, client},
+    keyboard,
+    output::OutputKind,
+    ui,
+};
+
+/// How bad the result of a check is
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Ok,
+    /// Something won't work, but `wl_keys` still will
+    Warning,
+    /// `wl_keys` won't work
+    Error,
+}
+
+/// The protocols to check for, how bad it is if they're missing, and why
+const PROTOCOLS: [(&str, Level, &str); 3] = [
+    (
+        "zwp_virtual_keyboard_manager_v1",
+        Level::Error,
+        "keys can't be sent without it, wlroots compositors like sway and Hyprland have it",
+    ),
+    (
+        "zwp_input_method_manager_v2",
+        Level::Warning,
+        "auto show, text input and suggestions are disabled without it",
+    ),
+    (
+        "zwlr_layer_shell_v1",
+        Level::Warning,
+        "eww needs it to show the keyboard above other windows",
+    ),
+];
+
+/// Prints the results of the checks, counting the problems
+#[derive(Default)]
+struct Report {
+    warnings: u32,
+    errors: u32,
+}
+
+impl Report {
+    fn print(&mut self, level: Level, msg: impl Display) {
+        let prefix = match level {
+            Level::Ok => "ok",
+            Level::Warning => {
+                self.warnings += 1;
+                "warning"
+            }
+            Level::Error => {
+                self.errors += 1;
+                "error"
+            }
+        };
+
+        println!("{prefix}: {msg}");
+    }
+}
+
+/// Check the protocols and seats
+fn check_compositor(report: &mut Report, args: &StartArgs) {
+    let probe = Connection::connect_to_env()
+        .map_err(anyhow::Error::from)
+        .and_then(|conn| keyboard::probe(&conn));
+    let (registry, seats) = match probe {
+        Ok(probe) => probe,
+        Err(err) => {
+            report.print(
+                Level::Error,
+                format!("Can't connect to the compositor, is $WAYLAND_DISPLAY set? {err:#}"),
+            );
+            return;
+        }
+    };
+
+    for (interface, level, reason) in PROTOCOLS {
+        match registry.find(interface) {
+            Some((_, global)) => {
+                report.print(Level::Ok, format!("{interface} v{}", global.version));
+            }
+            // The keys don't go through the compositor with the other outputs
+            None if interface == "zwp_virtual_keyboard_manager_v1"
+                && args.output != OutputKind::Wayland =>
+            {
+                report.print(
+                    Level::Ok,
+                    format!("{interface} is missing, but it isn't needed with this --output"),
+                );
+            }
+            None => report.print(level, format!("{interface} is missing, {reason}")),
+        }
+    }
+
+    let seat_names = seats
+        .iter()
+        .map(|seat| seat.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let chosen = match daemon::chosen_seats(args) {
+        Ok(chosen) => chosen,
+        Err(err) => {
+            report.print(Level::Error, format!("{err:#}"));
+            Vec::new()
+        }
+    };
+    if seats.is_empty() {
+        report.print(Level::Error, "The compositor has no seats");
+        return;
+    }
+    for name in &chosen {
+        if !seats.iter().any(|seat| &seat.name == name) {
+            report.print(
+                Level::Warning,
+                format!(
+                    "Seat {name} not found, it will be used once it's added, \
+                    the seats are: {seat_names}"
+                ),
+            );
+        }
+    }
+
+    let seat = chosen
+        .iter()
+        .find_map(|name| seats.iter().find(|seat| &seat.name == name))
+        .or_else(|| seats.first());
+    match seat {
+        Some(seat) if seat.has_keyboard => report.print(
+            Level::Ok,
+            format!("Seat {} has a keyboard to copy the keymap from", seat.name),
+        ),
+        Some(seat) => report.print(
+            Level::Warning,
+            format!(
+                "Seat {} has no keyboard, so the fallback keymap will be used, \
+                set it with --fallback-keymap",
+                seat.name
+            ),
+        ),
+        None => {}
+    }
+}
+
+/// Check the keys can be sent through /dev/uinput, if it's used
+#[cfg(feature = "uinput")]
+fn check_uinput(report: &mut Report, args: &StartArgs) {
+    if args.output != OutputKind::Uinput {
+        return;
+    }
+
+    match std::fs::OpenOptions::new().write(true).open("/dev/uinput") {
+        Ok(_) => report.print(Level::Ok, "/dev/uinput can be written to"),
+        Err(err) => report.print(
+            Level::Error,
+            format!(
+                "/dev/uinput can't be opened ({err}), \
+                load the uinput module and make sure you're in the input group"
+            ),
+        ),
+    }
+}
+
+/// Check the keymaps given in the args compile
+fn check_keymaps(report: &mut Report, args: &StartArgs) {
+    let keymaps = [
+        ("--fallback-keymap", Some(&args.fallback_keymap)),
+        ("--keymap", args.keymap.as_ref()),
+    ];
+
+    for (arg, spec) in keymaps {
+        let Some(spec) = spec else {
+            continue;
+        };
+
+        match spec.text() {
+            Ok(_) => report.print(Level::Ok, format!("The keymap from {arg} compiles")),
+            Err(err) => report.print(
+                Level::Error,
+                format!("The keymap from {arg} is invalid: {err:#}"),
+            ),
+        }
+    }
+}
+
+/// Check everything `wl_keys` needs, printing what's wrong and how to fix it.
+///
+/// This takes the same args as `daemon start`, so they can be checked too.
+pub async fn doctor(args: &StartArgs) -> Result<()> {
+    let mut report = Report::default();
+
+    check_compositor(&mut report, args);
+    #[cfg(feature = "uinput")]
+    check_uinput(&mut report, args);
+    check_keymaps(&mut report, args);
+
+    match ui::check(args) {
+        Ok((config, version)) => report.print(
+            Level::Ok,
+            format!("{version} with the config in {}", config.display()),
+        ),
+        // The daemon still runs without eww, just without the on-screen ui
+        Err(err) => report.print(
+            Level::Warning,
+            format!("The on-screen ui is unavailable: {err:#}"),
+        ),
+    }
+
+    if client().await.is_ok() {
+        println!("The daemon is running");
+    } else {
+        println!("The daemon isn't running, start it with 'wl_keys daemon start'");
+    }
+
+    if report.errors > 0 {
+        bail!(
+            "Found {} errors and {} warnings, wl_keys won't work until the errors are fixed",
+            report.errors,
+            report.warnings
+        );
+    }
+
+    if report.warnings > 0 {
+        println!("Found {} warnings", report.warnings);
+    }
+
+    Ok(())
+}
@@ -1,18 +1,21 @@
 use std::{
-    collections::HashMap,
-    os::fd::{AsFd, OwnedFd},
+    io::ErrorKind,
+    os::fd::{AsRawFd, RawFd},
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Context, Result};
+use tokio::sync::broadcast;
 use wayland_client::{
+    backend::WaylandError,
     delegate_noop,
     protocol::{
         wl_display::WlDisplay,
         wl_keyboard::{self, WlKeyboard},
         wl_registry::{self, WlRegistry},
-        wl_seat::WlSeat,
+        wl_seat::{self, WlSeat},
     },
-    Connection, Dispatch, EventQueue, Proxy, QueueHandle,
+    Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum,
 };
 use wayland_protocols_misc::{
     zwp_input_method_v2::client::{
@@ -25,19 +28,19 @@ use wayland_protocols_misc::{
     },
 };
 
-use crate::proto::Modifier;
-
-/// This is taken from the real `WlKeyboard`,
-/// and passed as the keymap for my virtual keyboard.
-struct Keymap {
-    fd: OwnedFd,
-    size: u32,
-}
+use crate::{
+    content::ContentType,
+    keymap::{self, Keymap, KeymapSpec},
+    output::{Output, OutputKind, WaylandOutput},
+    proto::{self, event::Event as ProtoEvent, KeymapSource, Modifier},
+    registry::{self, Registry},
+    ui,
+};
 
 /// Hold the modifier state
 // This is not a state machine
 #[allow(clippy::struct_excessive_bools)]
-#[derive(Default)]
+#[derive(Clone, Default)]
 struct ModState {
     shift: bool,
     ctrl: bool,
@@ -45,40 +48,120 @@ struct ModState {
     cmd: bool,
 }
 
-impl ModState {
-    pub fn to_bitflags(&self) -> u32 {
-        fn bool_mask(b: bool) -> u32 {
-            u32::MAX * u32::from(b)
+/// A seat advertised by the compositor
+struct SeatInfo {
+    /// The name of the global, used to match the seat's events
+    global: u32,
+    seat: WlSeat,
+    /// Like "seat0"
+    name: String,
+    /// Whether the seat has a real keyboard to take the keymap from
+    has_keyboard: bool,
+    /// The virtual keyboard on this seat,
+    /// only made if the keys are sent through wayland
+    virtual_kbd: Option<WaylandOutput>,
+    /// Whether keys are being sent to this seat
+    active: bool,
+}
+
+impl Drop for SeatInfo {
+    fn drop(&mut self) {
+        // This was added in v5, the virtual keyboard destroys itself
+        if self.seat.version() >= 5 {
+            self.seat.release();
+        }
+    }
+}
+
+/// The state of the focused text field.
+///
+/// The input method double-buffers this, so it only takes effect on `done`.
+#[derive(Clone, Default, PartialEq, Eq)]
+struct InputState {
+    /// Whether a text field is focused
+    active: bool,
+    content_type: ContentType,
+    /// Only set if the text field supports it
+    surrounding_text: Option<SurroundingText>,
+}
+
+/// The text around the cursor in the focused text field
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct SurroundingText {
+    /// The text, usually just around the cursor instead of the whole field
+    pub text: String,
+    /// The byte offset of the cursor in `text`
+    pub cursor: u32,
+    /// The byte offset of the other end of the selection,
+    /// this is the same as `cursor` if nothing is selected
+    pub anchor: u32,
+}
+
+impl From<SurroundingText> for proto::SurroundingText {
+    fn from(surrounding_text: SurroundingText) -> Self {
+        Self {
+            text: surrounding_text.text,
+            cursor: surrounding_text.cursor,
+            anchor: surrounding_text.anchor,
         }
+    }
+}
 
-        // These values are set by the keymap,
-        // so I found these out with 'wev' on my real kbd
-        (bool_mask(self.shift) & 0x01)
-            | (bool_mask(self.ctrl) & 0x04)
-            | (bool_mask(self.cmd) & 0x40)
-            | (bool_mask(self.alt) & 0x08)
+/// Sends events to any subscribed clients
+#[derive(Clone)]
+struct Events(broadcast::Sender<proto::Event>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self(broadcast::channel(32).0)
     }
 }
 
-struct Global {
-    /// The name of the object
-    /// Really this is more of an id,
-    /// but I'll keep consistency with wayland
-    name: u32,
-    /// The version of the implemented protocol
-    version: u32,
+impl Events {
+    fn send(&self, event: ProtoEvent) {
+        // This only fails if no one is listening
+        let _ = self.0.send(proto::Event { event: Some(event) });
+    }
 }
 
+// These are independent flags, not a state machine
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Default)]
 struct State {
-    globals: HashMap<String, Global>,
+    registry: Registry,
+    // All of the seats, in the order they were advertised
+    seats: Vec<SeatInfo>,
+    // The names of the seats to send keys to, the first seat if empty
+    chosen_seats: Vec<String>,
+    // This is taken from the real `WlKeyboard`,
+    // and passed as the keymap for my virtual keyboard
     keymap: Option<Keymap>,
     // Whether it will automatically open and close
     auto: bool,
+    // Whether the ui is currently shown
+    ui_open: bool,
+    // How long to wait after a deactivate before hiding,
+    // so hopping between text fields doesn't flicker the ui
+    hide_delay: Duration,
+    // When the ui should be hidden, if a hide is pending
+    hide_at: Option<Instant>,
     mods: ModState,
+    input: InputState,
+    pending_input: InputState,
+    // How many done events have been received,
+    // the input method's requests have to reference this
+    serial: u32,
+    // Whether another IME has the input method
+    input_method_taken: bool,
+    // How often to try to get the input method back
+    input_method_retry: Option<Duration>,
+    // When to next try to get the input method back
+    input_method_retry_at: Option<Instant>,
+    events: Events,
+    // Where the key events are sent
+    output: OutputKind,
 }
 
-delegate_noop!(State: ignore WlSeat);
 delegate_noop!(State: ignore ZwpVirtualKeyboardManagerV1);
 delegate_noop!(State: ignore ZwpVirtualKeyboardV1);
 delegate_noop!(State: ignore ZwpInputMethodManagerV2);
@@ -94,15 +177,59 @@ impl Dispatch<ZwpInputMethodV2, ()> for State {
     ) {
         use zwp_input_method_v2::Event;
 
-        if !state.auto {
-            return;
+        match event {
+            // This resets the rest of the state
+            Event::Activate => {
+                state.pending_input = InputState {
+                    active: true,
+                    ..InputState::default()
+                };
+            }
+            Event::Deactivate => state.pending_input.active = false,
+            Event::ContentType { hint, purpose } => {
+                state.pending_input.content_type = ContentType::new(hint.into(), purpose.into());
+            }
+            Event::SurroundingText {
+                text,
+                cursor,
+                anchor,
+            } => {
+                state.pending_input.surrounding_text = Some(SurroundingText {
+                    text,
+                    cursor,
+                    anchor,
+                });
+            }
+            Event::Done => {
+                state.serial = state.serial.wrapping_add(1);
+                state.apply_input();
+            }
+            Event::Unavailable => state.input_method_unavailable(),
+            _ => (),
         }
+    }
+}
 
-        let _ = match event {
-            Event::Activate => crate::ui::open(),
-            Event::Deactivate => crate::ui::close(),
-            _ => Ok(()),
+impl Dispatch<WlSeat, u32> for State {
+    fn event(
+        state: &mut Self,
+        _seat: &WlSeat,
+        event: wl_seat::Event,
+        global: &u32,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let Some(info) = state.seats.iter_mut().find(|info| info.global == *global) else {
+            return;
         };
+
+        match event {
+            wl_seat::Event::Capabilities {
+                capabilities: WEnum::Value(capabilities),
+            } => info.has_keyboard = capabilities.contains(wl_seat::Capability::Keyboard),
+            wl_seat::Event::Name { name } => info.name = name,
+            _ => (),
+        }
     }
 }
 
@@ -115,40 +242,227 @@ impl Dispatch<WlKeyboard, ()> for State {
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
     ) {
-        match event {
-            wl_keyboard::Event::Keymap { format, fd, size }
-                if format == wayland_client::WEnum::Value(wl_keyboard::KeymapFormat::XkbV1) =>
-            {
-                state.keymap = Some(Keymap { fd, size });
-            }
-            _ => (),
-        };
+        if let wl_keyboard::Event::Keymap {
+            format: WEnum::Value(wl_keyboard::KeymapFormat::XkbV1),
+            fd,
+            size,
+        } = event
+        {
+            state.keymap = Some(Keymap { fd, size });
+        }
     }
 }
 
 impl Dispatch<WlRegistry, ()> for State {
     fn event(
         state: &mut Self,
-        _registry: &WlRegistry,
+        registry: &WlRegistry,
         event: wl_registry::Event,
         _user_state: &(),
         _conn: &Connection,
-        _qh: &QueueHandle<Self>,
+        qh: &QueueHandle<Self>,
     ) {
-        if let wl_registry::Event::Global {
-            name,
-            interface,
-            version,
-        } = event
-        {
-            state.globals.insert(interface, Global { name, version });
+        match event {
+            wl_registry::Event::Global {
+                name,
+                interface,
+                version,
+            } => {
+                let is_seat = interface == WlSeat::interface().name;
+                state.registry.add(name, interface, version);
+
+                // Every seat is bound to get its name,
+                // so the user can choose between them
+                if is_seat {
+                    match registry::negotiate(WlSeat::interface(), version) {
+                        Ok(version) => {
+                            state.registry.set_bound(name, version);
+                            state.seats.push(SeatInfo {
+                                global: name,
+                                seat: registry.bind(name, version, qh, name),
+                                name: String::new(),
+                                has_keyboard: false,
+                                virtual_kbd: None,
+                                active: false,
+                            });
+                        }
+                        Err(err) => eprintln!("Ignoring a seat: {err:#}"),
+                    }
+                }
+            }
+            wl_registry::Event::GlobalRemove { name } => {
+                state.registry.remove(name);
+
+                // Dropping it destroys its virtual keyboard,
+                // anything else on it is moved off by `update_seats`
+                if let Some(i) = state.seats.iter().position(|info| info.global == name) {
+                    let info = state.seats.remove(i);
+                    eprintln!("Seat {} was removed", info.name);
+                }
+            }
+            _ => (),
         }
     }
 }
 
 impl State {
+    /// Show or hide the ui, keeping track of its visibility.
+    ///
+    /// eww is run on the ui worker, so wait on what's returned for whether it worked,
+    /// or drop it and the ui worker logs any error.
+    fn set_ui(&mut self, open: bool) -> ui::Pending {
+        self.hide_at = None;
+
+        if self.ui_open != open {
+            self.ui_open = open;
+            self.events.send(ProtoEvent::Ui(proto::UiStatus { open }));
+        }
+
+        if open {
+            let pending = ui::open();
+            // The content type isn't sent while the ui is closed,
+            // so it's caught up here
+            self.update_ui_content();
+            pending
+        } else {
+            ui::close()
+        }
+    }
+
+    /// Tell the ui about the focused text field's content type,
+    /// the ui worker logs it if this fails.
+    ///
+    /// This does nothing while the ui is closed,
+    /// as eww may not be running, and it's sent when the ui opens instead.
+    fn update_ui_content(&self) {
+        if self.ui_open {
+            let _ = ui::update_content(&self.input.content_type);
+        }
+    }
+
+    /// The input method won't be used by the compositor anymore,
+    /// usually because another IME like fcitx5 has it.
+    fn input_method_unavailable(&mut self) {
+        self.input_method_retry_at = self.input_method_retry.map(|retry| Instant::now() + retry);
+
+        // This happens on every failed retry
+        if self.input_method_taken {
+            return;
+        }
+
+        eprintln!("The input method is taken by another IME, auto mode will keep the ui open");
+        self.input_method_taken = true;
+        self.input = InputState::default();
+        self.pending_input = InputState::default();
+        self.events.send(ProtoEvent::InputMethod(
+            proto::InputMethodState::Taken.into(),
+        ));
+
+        // Without knowing when a text field is focused, the ui just stays open
+        if self.auto && !self.ui_open {
+            self.set_ui(true);
+        }
+    }
+
+    /// The input method can be used again after another IME had it
+    fn input_method_regained(&mut self) {
+        eprintln!("Got the input method back");
+        self.input_method_taken = false;
+        self.events.send(ProtoEvent::InputMethod(
+            proto::InputMethodState::Available.into(),
+        ));
+
+        // Auto mode kept the ui open while it was taken,
+        // so it's hidden like a text field was unfocused unless one is focused
+        if self.auto && self.ui_open && !self.input.active {
+            self.hide_at = Some(Instant::now() + self.hide_delay);
+        }
+    }
+
+    /// Apply the pending input state after a `done`
+    fn apply_input(&mut self) {
+        let old = std::mem::replace(&mut self.input, self.pending_input.clone());
+
+        if self.input.content_type != old.content_type {
+            self.events
+                .send(ProtoEvent::ContentType(self.input.content_type.into()));
+            self.update_ui_content();
+        }
+
+        if self.input.surrounding_text != old.surrounding_text {
+            self.events.send(ProtoEvent::SurroundingText(
+                self.input
+                    .surrounding_text
+                    .clone()
+                    .unwrap_or_default()
+                    .into(),
+            ));
+        }
+
+        if !self.auto || self.input.active == old.active {
+            return;
+        }
+
+        if self.input.active {
+            // This also cancels a pending hide
+            self.hide_at = None;
+
+            if !self.ui_open {
+                self.set_ui(true);
+            }
+        } else if self.ui_open {
+            self.hide_at = Some(Instant::now() + self.hide_delay);
+        }
+    }
+
+    /// Reset the input state, like when the input method is recreated
+    fn reset_input(&mut self) {
+        self.pending_input = InputState::default();
+        self.apply_input();
+        self.serial = 0;
+    }
+
+    /// The globals of the seats keys are sent to,
+    /// the chosen seats that exist or the first seat if none of them do.
+    ///
+    /// The first is where the input method and keymap come from.
+    fn active_seats(&self) -> Vec<u32> {
+        let chosen = self
+            .chosen_seats
+            .iter()
+            .filter_map(|name| self.seats.iter().find(|info| &info.name == name))
+            .map(|info| info.global)
+            .collect::<Vec<_>>();
+
+        if chosen.is_empty() {
+            self.seats
+                .first()
+                .map(|info| info.global)
+                .into_iter()
+                .collect()
+        } else {
+            chosen
+        }
+    }
+
+    /// Get the globals from the registry,
+    /// and the names and capabilities of the seats
+    fn get_globals(
+        &mut self,
+        conn: &Connection,
+        event_queue: &mut EventQueue<Self>,
+    ) -> Result<WlRegistry> {
+        let registry = conn.display().get_registry(&event_queue.handle(), ());
+        // The first gets the globals, the second gets the seats' events
+        event_queue.roundtrip(self)?;
+        event_queue.roundtrip(self)?;
+        Ok(registry)
+    }
+
+    /// Bind the first global implementing `T`,
+    /// with the newest version both sides support.
     pub fn bind_global<T: Proxy + 'static>(
-        &self,
+        &mut self,
         registry: &wl_registry::WlRegistry,
         qh: &QueueHandle<Self>,
     ) -> Result<T>
@@ -156,207 +470,1462 @@ impl State {
         Self: Dispatch<T, ()>,
     {
         let interface = T::interface();
-        let global = self
-            .globals
-            .get(interface.name)
+        let (name, global) = self
+            .registry
+            .find(interface.name)
             .context(format!("{interface} not found"))?;
+        let version = registry::negotiate(interface, global.version)?;
 
-        if global.version > interface.version {
-            bail!(
-                "{} v{} exceeds the max supported version (v{})",
-                interface.name,
-                global.version,
-                interface.version
-            );
-        }
-
-        Ok(registry.bind::<T, _, _>(global.name, global.version, qh, ()))
+        self.registry.set_bound(name, version);
+        Ok(registry.bind::<T, _, _>(name, version, qh, ()))
     }
 }
 
+/// A seat's name and whether it has a keyboard
+pub struct SeatSummary {
+    /// Like "seat0"
+    pub name: String,
+    /// Whether the seat has a real keyboard to take the keymap from
+    pub has_keyboard: bool,
+}
+
+/// Find what the compositor advertises, the same way the keyboard does,
+/// without creating anything
+pub fn probe(conn: &Connection) -> Result<(Registry, Vec<SeatSummary>)> {
+    let mut event_queue = conn.new_event_queue();
+    let mut state = State::default();
+    let _registry = state.get_globals(conn, &mut event_queue)?;
+
+    let seats = state
+        .seats
+        .iter()
+        .map(|info| SeatSummary {
+            name: info.name.clone(),
+            has_keyboard: info.has_keyboard,
+        })
+        .collect();
+
+    Ok((state.registry, seats))
+}
+
 /// The virtual keyboard
 pub struct Keyboard {
     state: State,
 
-    _conn: Connection,
+    conn: Connection,
     _display: WlDisplay,
     event_queue: EventQueue<State>,
-    _qh: QueueHandle<State>,
+    qh: QueueHandle<State>,
     _registry: WlRegistry,
 
-    _seat: WlSeat,
-    _keyboard_manager: ZwpVirtualKeyboardManagerV1,
-    keyboard: ZwpVirtualKeyboardV1,
+    // The first active seat, which the input method and real keyboard are on
+    seat: Option<WlSeat>,
+    // These are missing on compositors without zwp_input_method_v2
+    input_method_manager: Option<ZwpInputMethodManagerV2>,
+    input_method: Option<ZwpInputMethodV2>,
+    // This is missing if the seat has no keyboard, like on tablets
+    real_kbd: Option<WlKeyboard>,
+    // This is only bound if the keys are sent through wayland,
+    // each seat gets its own virtual keyboard from it
+    virtual_kbd_manager: Option<ZwpVirtualKeyboardManagerV1>,
+    // Used instead of the seats' virtual keyboards, like uinput which isn't on a seat
+    output: Option<Box<dyn Output>>,
+    /// The contents of the keymap given to the virtual keyboard
+    keymap: Vec<u8>,
+    /// Where the keymap given to the virtual keyboard came from
+    keymap_source: KeymapSource,
+    /// The latest keymap from the real keyboard,
+    /// kept so it can be used again after a custom keymap
+    seat_keymap: Option<Keymap>,
+    /// Used when the seat doesn't give a keymap
+    fallback_keymap: KeymapSpec,
+    /// False once the connection to the compositor is lost
+    connected: bool,
 }
 
 impl Keyboard {
     /// Creates the virtual keyboard
-    pub fn new() -> Result<Self> {
-        let mut state = State::default();
+    pub fn new(output: OutputKind) -> Result<Self> {
+        let state = State {
+            output,
+            ..State::default()
+        };
+        Self::connect(Connection::connect_to_env()?, state, KeymapSpec::Default)
+    }
 
-        let conn = Connection::connect_to_env()?;
+    /// Creates the virtual keyboard on a connection to a test compositor
+    #[cfg(test)]
+    pub fn from_connection(conn: Connection) -> Result<Self> {
+        Self::connect(conn, State::default(), KeymapSpec::Default)
+    }
+
+    /// Create the virtual keyboard,
+    /// starting with the settings in `state`
+    fn connect(conn: Connection, mut state: State, fallback_keymap: KeymapSpec) -> Result<Self> {
         let display = conn.display();
         let mut event_queue = conn.new_event_queue();
         let qh = event_queue.handle();
 
-        // Get the globals from the registry
-        let registry = display.get_registry(&qh, ());
-        event_queue.roundtrip(&mut state)?;
-
-        let seat = state.bind_global::<WlSeat>(&registry, &qh)?;
-        // Take the keyboard
-        seat.get_keyboard(&qh, ());
-        event_queue.roundtrip(&mut state)?;
+        let registry = state.get_globals(&conn, &mut event_queue)?;
+        if state.seats.is_empty() {
+            bail!("No seat found");
+        }
 
         // zwp_input_method_v2 is used for clients to become their own input method,
         // that manages text instead of just keypresses like the virtual keyboard.
-        // I'm not using it to input the text, only for when to show and hide the keyboard.
+        // It's used for when to show and hide the keyboard,
+        // and to commit text that the keymap can't type.
         // This article was a great explainer for this
         // https://dorotac.eu/posts/input_method/
-        let input_method_manager = state.bind_global::<ZwpInputMethodManagerV2>(&registry, &qh)?;
-        input_method_manager.get_input_method(&seat, &qh, ());
-
-        // Create the virtual keyboard
-        let keyboard_manager = state.bind_global::<ZwpVirtualKeyboardManagerV1>(&registry, &qh)?;
-        let keyboard = keyboard_manager.create_virtual_keyboard(&seat, &qh, ());
+        // Some compositors don't have it, but the virtual keyboard still works without it.
+        let input_method_manager =
+            match state.bind_global::<ZwpInputMethodManagerV2>(&registry, &qh) {
+                Ok(manager) => Some(manager),
+                Err(err) => {
+                    eprintln!("{err}, auto show and text input are disabled");
+                    None
+                }
+            };
 
-        // Set the keymap for the virtual keyboard
-        if let Some(Keymap { fd, size }) = state.keymap.take() {
-            keyboard.keymap(1, fd.as_fd(), size);
-        } else {
-            bail!("Keymap not found");
-        }
-
-        event_queue.roundtrip(&mut state)?;
+        // The virtual keyboards are made on each seat by `update_seats`,
+        // compositors without them can still be used through uinput
+        let (virtual_kbd_manager, output): (_, Option<Box<dyn Output>>) = match state.output {
+            OutputKind::Wayland => (
+                Some(state.bind_global::<ZwpVirtualKeyboardManagerV1>(&registry, &qh)?),
+                None,
+            ),
+            #[cfg(feature = "uinput")]
+            OutputKind::Uinput => (None, Some(Box::new(crate::output::UinputOutput::new()?))),
+        };
 
-        Ok(Self {
+        let mut keyboard = Self {
             state,
 
-            _conn: conn,
+            conn,
             _display: display,
             event_queue,
-            _qh: qh,
+            qh,
             _registry: registry,
 
-            _seat: seat,
-            _keyboard_manager: keyboard_manager,
-            keyboard,
-        })
-    }
+            seat: None,
+            real_kbd: None,
+            input_method_manager,
+            input_method: None,
+            virtual_kbd_manager,
+            output,
+            keymap: Vec::new(),
+            keymap_source: KeymapSource::Seat,
+            seat_keymap: None,
+            fallback_keymap,
+            connected: true,
+        };
 
-    fn time() -> u32 {
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or(std::time::Duration::from_millis(0))
-            .as_millis() as u32
-    }
+        // Make the virtual keyboards and input method,
+        // and take the keyboard, this is kept to track keymap changes
+        keyboard.update_seats();
+        keyboard.event_queue.roundtrip(&mut keyboard.state)?;
 
-    /// Set the state of a key
-    pub fn key(&self, key: u32, pressed: bool) -> Result<()> {
-        self.keyboard.key(Self::time(), key, pressed.into());
-        self.event_queue.flush()?;
-        Ok(())
+        // Set the keymap for the virtual keyboard,
+        // which needs one before it can send keys
+        if !keyboard.update_keymap()? {
+            eprintln!("The seat has no keymap, using the fallback keymap");
+            keyboard.upload_fallback_keymap()?;
+        }
+
+        keyboard.roundtrip()?;
+
+        Ok(keyboard)
     }
 
-    /// Blocks until all events are sent and processed
-    pub fn roundtrip(&mut self) -> Result<()> {
-        self.event_queue.roundtrip(&mut self.state)?;
+    /// Connect to the compositor again after the connection was lost,
+    /// keeping the settings and the subscribed clients.
+    pub fn reconnect(&mut self) -> Result<()> {
+        let state = State {
+            auto: self.state.auto,
+            ui_open: self.state.ui_open,
+            // A hide that was due while disconnected happens on the next tick
+            hide_at: self.state.hide_at,
+            hide_delay: self.state.hide_delay,
+            input_method_retry: self.state.input_method_retry,
+            mods: self.state.mods.clone(),
+            events: self.state.events.clone(),
+            output: self.state.output,
+            // Seats that don't exist yet are used if they're added
+            chosen_seats: self.state.chosen_seats.clone(),
+            ..State::default()
+        };
+        let conn = Connection::connect_to_env()?;
+        let mut keyboard = Self::connect(conn, state, self.fallback_keymap.clone())?;
+
+        if self.keymap_source == KeymapSource::Custom {
+            keyboard.set_keymap(&Keymap::new(&self.keymap)?)?;
+        }
+
+        if self.state.input_method_taken
+            && keyboard.input_method_state() == proto::InputMethodState::Available
+        {
+            keyboard.state.input_method_regained();
+        } else if keyboard.input_method_state() != self.input_method_state() {
+            keyboard.state.events.send(ProtoEvent::InputMethod(
+                keyboard.input_method_state().into(),
+            ));
+        }
+
+        *self = keyboard;
+
         Ok(())
     }
 
-    /// Enable input detection
-    pub fn auto_enable(&mut self) {
-        self.state.auto = true;
+    /// Info about the seat the input method is on,
+    /// this is `None` if it was removed
+    fn seat_info(&self) -> Option<&SeatInfo> {
+        let seat = self.seat.as_ref()?;
+        self.state.seats.iter().find(|info| &info.seat == seat)
     }
 
-    /// Disable input detection
-    pub fn auto_disable(&mut self) {
-        self.state.auto = false;
+    /// Take or release the real keyboard when the seat's capabilities change
+    fn update_real_kbd(&mut self) {
+        let has_keyboard = self.seat_info().is_some_and(|info| info.has_keyboard);
+
+        match (&self.real_kbd, &self.seat, has_keyboard) {
+            (None, Some(seat), true) => self.real_kbd = Some(seat.get_keyboard(&self.qh, ())),
+            (Some(real_kbd), _, false) => {
+                // This was added in v3
+                if real_kbd.version() >= 3 {
+                    real_kbd.release();
+                }
+                self.real_kbd = None;
+            }
+            _ => (),
+        }
     }
 
-    /// Toggle input detection
-    pub fn auto_toggle(&mut self) {
-        self.state.auto = !self.state.auto;
+    /// Bring the seats up to date after they've changed.
+    ///
+    /// New seats get a virtual keyboard with the current keymap,
+    /// seats that stop being active have their modifiers cleared,
+    /// and the input method follows the first active seat.
+    fn update_seats(&mut self) {
+        let active = self.state.active_seats();
+        let mods = self.mods_pressed();
+        let has_keymap = !self.keymap.is_empty();
+
+        for info in &mut self.state.seats {
+            if info.virtual_kbd.is_none() {
+                if let Some(manager) = &self.virtual_kbd_manager {
+                    let virtual_kbd = manager.create_virtual_keyboard(&info.seat, &self.qh, ());
+                    let mut virtual_kbd = WaylandOutput::new(virtual_kbd, self.conn.clone());
+
+                    // It needs a keymap before it can send keys
+                    if has_keymap {
+                        if let Err(err) =
+                            Keymap::new(&self.keymap).and_then(|keymap| virtual_kbd.keymap(&keymap))
+                        {
+                            eprintln!("Failed to give seat {} the keymap: {err:#}", info.name);
+                        }
+                    }
+                    info.virtual_kbd = Some(virtual_kbd);
+                }
+            }
+
+            let is_active = active.contains(&info.global);
+            if is_active == info.active {
+                continue;
+            }
+            info.active = is_active;
+
+            // The modifiers can't be sent until there's a keymap,
+            // that sends them to the active seats itself
+            if let (Some(virtual_kbd), true) = (&mut info.virtual_kbd, has_keymap) {
+                let mods = if is_active { mods.as_slice() } else { &[] };
+                if let Err(err) = virtual_kbd.modifiers(mods) {
+                    eprintln!("Failed to set seat {}'s modifiers: {err:#}", info.name);
+                }
+            }
+        }
+
+        let first = active
+            .first()
+            .and_then(|&global| self.state.seats.iter().find(|info| info.global == global))
+            .map(|info| info.seat.clone());
+        if first != self.seat {
+            self.move_input_method(first);
+        }
+
+        self.update_real_kbd();
     }
 
-    /// Get the auto status
-    #[must_use]
-    pub const fn auto_query(&self) -> bool {
-        self.state.auto
+    /// Move the input method and real keyboard to another seat,
+    /// destroying the ones on the old seat
+    fn move_input_method(&mut self, seat: Option<WlSeat>) {
+        if let Some(input_method) = self.input_method.take() {
+            input_method.destroy();
+        }
+        if let Some(real_kbd) = self.real_kbd.take() {
+            if real_kbd.version() >= 3 {
+                real_kbd.release();
+            }
+        }
+
+        self.seat = seat;
+        self.input_method = self
+            .input_method_manager
+            .as_ref()
+            .zip(self.seat.as_ref())
+            .map(|(manager, seat)| manager.get_input_method(seat, &self.qh, ()));
+
+        self.state.reset_input();
+        self.state.input_method_retry_at = None;
+        // If another IME has it on this seat too, unavailable is sent straight away
+        if std::mem::take(&mut self.state.input_method_taken) {
+            self.state.input_method_regained();
+        }
+
+        // The old seat's keymap shouldn't be used, this one's is sent after it
+        self.seat_keymap = None;
+
+        let Some(info) = self.seat_info() else {
+            eprintln!("There are no seats to use");
+            return;
+        };
+        eprintln!("Using seat {} for the input method and keymap", info.name);
+
+        let has_keyboard = info.has_keyboard;
+        if self.keymap_source == KeymapSource::Seat && !has_keyboard && !self.keymap.is_empty() {
+            if let Err(err) = self.upload_fallback_keymap() {
+                eprintln!("Failed to use the fallback keymap: {err:#}");
+            }
+        }
     }
 
-    fn send_mods(&self) -> Result<()> {
-        let latched = self.state.mods.to_bitflags();
+    /// Set the keymap to use when the seat doesn't have one,
+    /// switching to it if the current keymap is the fallback.
+    pub fn set_fallback_keymap(&mut self, spec: KeymapSpec) -> Result<()> {
+        self.fallback_keymap = spec;
 
-        self.keyboard.modifiers(0, latched, 0, 0);
-        self.event_queue.flush()?;
+        if self.keymap_source == KeymapSource::Fallback {
+            self.upload_fallback_keymap()?;
+        }
 
         Ok(())
     }
 
-    /// Press a modifier
-    pub fn mod_press(&mut self, modifier: Modifier) -> Result<()> {
-        match modifier {
-            Modifier::Shift => self.state.mods.shift = true,
-            Modifier::Ctrl => self.state.mods.ctrl = true,
-            Modifier::Alt => self.state.mods.alt = true,
-            Modifier::Cmd => self.state.mods.cmd = true,
+    /// Upload the fallback keymap
+    fn upload_fallback_keymap(&mut self) -> Result<()> {
+        let keymap = Keymap::from_text(&self.fallback_keymap.text()?)?;
+        self.upload_keymap(&keymap, KeymapSource::Fallback)
+    }
+
+    /// Upload the keymap from the real keyboard if it's changed,
+    /// returning whether there was one.
+    fn update_keymap(&mut self) -> Result<bool> {
+        let Some(keymap) = self.state.keymap.take() else {
+            return Ok(false);
+        };
+
+        // Once the virtual keyboard is used, the compositor sends its keymap back,
+        // so this avoids uploading the same keymap forever
+        if keymap.contents()? == self.keymap {
+            return Ok(true);
         }
 
-        self.send_mods()?;
+        // A custom keymap isn't replaced, but the real one is kept for a reset
+        if self.keymap_source != KeymapSource::Custom {
+            self.upload_keymap(&keymap, KeymapSource::Seat)?;
+        }
+        self.seat_keymap = Some(keymap);
+
+        Ok(true)
+    }
+
+    /// `update_keymap` for when the connection is still fine if it fails,
+    /// the error is logged and the previous keymap is kept
+    fn update_keymap_logged(&mut self) {
+        if let Err(err) = self.update_keymap() {
+            eprintln!("Failed to use the seat's new keymap, keeping the old one: {err:#}");
+        }
+    }
+
+    /// Give a keymap to every seat's virtual keyboard if it's changed,
+    /// the inactive seats have it ready for when they're used
+    fn upload_keymap(&mut self, keymap: &Keymap, source: KeymapSource) -> Result<()> {
+        let contents = keymap.contents()?;
+        let changed = contents != self.keymap;
+
+        if !changed && source == self.keymap_source {
+            return Ok(());
+        }
+
+        if changed {
+            match &mut self.output {
+                Some(output) => output.keymap(keymap)?,
+                None => {
+                    for virtual_kbd in self
+                        .state
+                        .seats
+                        .iter_mut()
+                        .filter_map(|info| info.virtual_kbd.as_mut())
+                    {
+                        virtual_kbd.keymap(keymap)?;
+                    }
+                }
+            }
+            // A new keymap resets the modifiers
+            self.send_mods()?;
+
+            if !self.keymap.is_empty() {
+                eprintln!("Keymap changed to {}", keymap::symbols(&contents));
+            }
+            self.keymap = contents;
+        }
+
+        self.keymap_source = source;
+        self.state
+            .events
+            .send(ProtoEvent::Keymap(self.keymap_status()));
 
         Ok(())
     }
 
-    /// Release a modifier
-    pub fn mod_release(&mut self, modifier: Modifier) -> Result<()> {
-        match modifier {
-            Modifier::Shift => self.state.mods.shift = false,
-            Modifier::Ctrl => self.state.mods.ctrl = false,
-            Modifier::Alt => self.state.mods.alt = false,
-            Modifier::Cmd => self.state.mods.cmd = false,
+    /// Send some requests to the input method,
+    /// then commit them so they're applied to the focused text field.
+    ///
+    /// Returns false if no text field is focused, so nothing was sent.
+    fn input_method_commit(&self, requests: impl FnOnce(&ZwpInputMethodV2)) -> Result<bool> {
+        let Some(input_method) = &self.input_method else {
+            return Ok(false);
+        };
+
+        if !self.state.input.active {
+            return Ok(false);
         }
 
-        self.send_mods()?;
+        requests(input_method);
+        // The compositor ignores the commit unless it
+        // references the latest done event it sent
+        input_method.commit(self.state.serial);
+        self.event_queue.flush()?;
+
+        Ok(true)
+    }
 
+    /// Blocks until all events are sent and processed.
+    ///
+    /// If this fails the connection is lost, and `reconnect` is needed.
+    pub fn roundtrip(&mut self) -> Result<()> {
+        if let Err(err) = self.event_queue.roundtrip(&mut self.state) {
+            self.connected = false;
+            return Err(err.into());
+        }
+
+        self.update_seats();
+        self.update_keymap_logged();
         Ok(())
     }
 
-    /// Toggle a modifier
-    pub fn mod_toggle(&mut self, modifier: Modifier) -> Result<()> {
-        match modifier {
-            Modifier::Shift => self.state.mods.shift = !self.state.mods.shift,
-            Modifier::Ctrl => self.state.mods.ctrl = !self.state.mods.ctrl,
-            Modifier::Alt => self.state.mods.alt = !self.state.mods.alt,
-            Modifier::Cmd => self.state.mods.cmd = !self.state.mods.cmd,
+    /// Handle the events that have already been read,
+    /// and send any requests that are waiting.
+    ///
+    /// If this fails the connection is lost, and `reconnect` is needed.
+    pub fn dispatch(&mut self) -> Result<()> {
+        if let Err(err) = self.event_queue.dispatch_pending(&mut self.state) {
+            self.connected = false;
+            return Err(err.into());
         }
 
-        self.send_mods()?;
+        self.update_seats();
+        self.update_keymap_logged();
+
+        if let Err(err) = self.event_queue.flush() {
+            self.connected = false;
+            return Err(err.into());
+        }
 
         Ok(())
     }
 
-    /// Get the modifier state
-    #[must_use]
-    pub const fn mod_query(&self, modifier: Modifier) -> bool {
-        match modifier {
-            Modifier::Shift => self.state.mods.shift,
-            Modifier::Ctrl => self.state.mods.ctrl,
-            Modifier::Alt => self.state.mods.alt,
-            Modifier::Cmd => self.state.mods.cmd,
+    /// Read everything the compositor has sent without blocking,
+    /// and handle it.
+    ///
+    /// If this fails the connection is lost, and `reconnect` is needed.
+    pub fn read_events(&mut self) -> Result<()> {
+        // Events that are already queued have to be handled before reading
+        self.dispatch()?;
+
+        if let Some(guard) = self.event_queue.prepare_read() {
+            match guard.read() {
+                // This reads until the socket is empty
+                Ok(_) => (),
+                Err(WaylandError::Io(err)) if err.kind() == ErrorKind::WouldBlock => (),
+                Err(err) => {
+                    self.connected = false;
+                    return Err(err.into());
+                }
+            }
         }
+
+        self.dispatch()
     }
 
-    /// Release all of the modifiers
-    pub fn mod_release_all(&mut self) -> Result<()> {
-        self.state.mods = ModState::default();
-        self.send_mods()?;
-        Ok(())
+    /// The wayland socket to wait on for events,
+    /// or `None` if there are events queued that need to be dispatched first.
+    #[must_use]
+    pub fn fd(&self) -> Option<RawFd> {
+        self.event_queue
+            .prepare_read()
+            .map(|guard| guard.connection_fd().as_raw_fd())
     }
 
-    /// Get a list of protocols supported
+    /// When `tick` next needs to be called,
+    /// or `None` if nothing is scheduled
     #[must_use]
-    pub fn protocols(&self) -> Vec<String> {
-        self.state.globals.keys().cloned().collect()
+    pub fn next_tick(&self) -> Option<Instant> {
+        [self.state.hide_at, self.state.input_method_retry_at]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Run anything that was scheduled for later,
+    /// like a delayed hide or getting the input method back
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+
+        if self.state.hide_at.is_some_and(|hide_at| hide_at <= now) {
+            self.state.set_ui(false);
+        }
+
+        if self
+            .state
+            .input_method_retry_at
+            .is_some_and(|retry_at| retry_at <= now)
+        {
+            if let Err(err) = self.retry_input_method() {
+                eprintln!("Failed to get the input method back: {err:#}");
+            }
+        }
+    }
+
+    /// Try to get the input method back after another IME had it
+    fn retry_input_method(&mut self) -> Result<()> {
+        let (Some(manager), Some(seat)) = (&self.input_method_manager, &self.seat) else {
+            return Ok(());
+        };
+
+        // An unavailable input method is inert, so a new one is needed
+        if let Some(input_method) = self.input_method.take() {
+            input_method.destroy();
+        }
+        self.input_method = Some(manager.get_input_method(seat, &self.qh, ()));
+        self.state.serial = 0;
+
+        // If it's still taken, unavailable is sent straight away and this is set again
+        self.state.input_method_retry_at = None;
+        self.roundtrip()?;
+
+        if self.state.input_method_retry_at.is_none() {
+            self.state.input_method_regained();
+        }
+
+        Ok(())
+    }
+
+    /// Set how long to wait after input stops before hiding the ui
+    pub const fn set_hide_delay(&mut self, hide_delay: Duration) {
+        self.state.hide_delay = hide_delay;
+    }
+
+    /// Set how often to try to get the input method back from another IME,
+    /// or `None` to not try
+    pub const fn set_input_method_retry(&mut self, retry: Option<Duration>) {
+        self.state.input_method_retry = retry;
+    }
+
+    fn send_mods(&mut self) -> Result<()> {
+        let mods = self.mods_pressed();
+        self.outputs()
+            .into_iter()
+            .try_for_each(|output| output.modifiers(&mods))
+    }
+
+    /// Where the keys are sent,
+    /// the output from `set_output` or the active seats' virtual keyboards
+    fn outputs(&mut self) -> Vec<&mut dyn Output> {
+        if let Some(output) = &mut self.output {
+            return vec![output.as_mut()];
+        }
+
+        self.state
+            .seats
+            .iter_mut()
+            .filter(|info| info.active)
+            .filter_map(|info| info.virtual_kbd.as_mut())
+            .map(|virtual_kbd| virtual_kbd as &mut dyn Output)
+            .collect()
+    }
+
+    /// Send the key events to a mock instead of the seats.
+    ///
+    /// It's given the current keymap and modifiers straight away.
+    #[cfg(test)]
+    pub fn set_output(&mut self, mut output: Box<dyn Output>) -> Result<()> {
+        output.keymap(&Keymap::new(&self.keymap)?)?;
+        self.output = Some(output);
+        self.send_mods()
+    }
+}
+
+/// What the daemon needs from a keyboard,
+/// so it can be tested against a fake one.
+pub trait KeyboardBackend: Send {
+    /// Whether the connection to the compositor is alive
+    #[must_use]
+    fn connected(&self) -> bool;
+
+    /// The name of the first active seat,
+    /// where the input method and keymap come from
+    #[must_use]
+    fn seat_name(&self) -> String;
+
+    /// The names of all the seats
+    #[must_use]
+    fn seat_names(&self) -> Vec<String>;
+
+    /// The names of the seats keys are sent to
+    #[must_use]
+    fn active_seat_names(&self) -> Vec<String>;
+
+    /// Send keys to the seats with these names,
+    /// or just the first seat if none are given.
+    ///
+    /// The input method and keymap come from the first of them.
+    /// Seats that don't exist yet are used once they're added,
+    /// so check them with `check_seats` first if they should exist.
+    fn set_seats(&mut self, names: &[String]) -> Result<()>;
+
+    /// Check there are seats with these names
+    fn check_seats(&self, names: &[String]) -> Result<()> {
+        let seats = self.seat_names();
+        if let Some(name) = names.iter().find(|name| !seats.contains(name)) {
+            bail!("Seat {name} not found, the seats are: {}", seats.join(", "));
+        }
+
+        Ok(())
+    }
+
+    /// Use a different keymap to the real keyboard,
+    /// until `reset_keymap` is called.
+    fn set_keymap(&mut self, keymap: &Keymap) -> Result<()>;
+
+    /// Go back to the real keyboard's keymap after `set_keymap`,
+    /// or the fallback if the seat doesn't have one.
+    fn reset_keymap(&mut self) -> Result<()>;
+
+    /// Set the state of a key
+    fn key(&mut self, key: u32, pressed: bool) -> Result<()>;
+
+    /// Find the keys that type `text` with the virtual keyboard's keymap,
+    /// and whether shift needs to be held for each
+    fn text_to_keys(&self, text: &str) -> Result<Vec<(u32, bool)>>;
+
+    /// Commit text to the focused text field through the input method.
+    ///
+    /// Returns false if no text field is focused, so nothing was sent.
+    fn commit_string(&self, text: &str) -> Result<bool>;
+
+    /// Show uncommitted text in the focused text field,
+    /// with the cursor range given as byte offsets into the text.
+    ///
+    /// Returns false if no text field is focused, so nothing was sent.
+    fn set_preedit(&self, text: &str, cursor_begin: i32, cursor_end: i32) -> Result<bool>;
+
+    /// Delete text around the cursor in the focused text field,
+    /// with the lengths given in bytes.
+    ///
+    /// Returns false if no text field is focused, so nothing was sent.
+    fn delete_surrounding_text(&self, before_length: u32, after_length: u32) -> Result<bool>;
+
+    /// Replace the bytes just before the cursor with `text`,
+    /// as one change to the focused text field.
+    ///
+    /// Returns false if no text field is focused, so nothing was sent.
+    fn replace_before_cursor(&self, length: u32, text: &str) -> Result<bool>;
+
+    /// Remove the uncommitted text from the focused text field.
+    ///
+    /// Returns false if no text field is focused, so nothing was sent.
+    fn clear_preedit(&self) -> Result<bool> {
+        self.set_preedit("", 0, 0)
+    }
+
+    /// Enable input detection
+    fn auto_enable(&mut self);
+
+    /// Disable input detection
+    fn auto_disable(&mut self);
+
+    /// Toggle input detection
+    fn auto_toggle(&mut self) {
+        if self.auto_query() {
+            self.auto_disable();
+        } else {
+            self.auto_enable();
+        }
+    }
+
+    /// Get the auto status
+    #[must_use]
+    fn auto_query(&self) -> bool;
+
+    /// Show the ui
+    fn ui_open(&mut self) -> ui::Pending;
+
+    /// Hide the ui
+    fn ui_close(&mut self) -> ui::Pending;
+
+    /// Toggle the ui
+    fn ui_toggle(&mut self) -> ui::Pending;
+
+    /// Get whether the ui is shown
+    #[must_use]
+    fn ui_query(&self) -> bool;
+
+    /// Press a modifier
+    fn mod_press(&mut self, modifier: Modifier) -> Result<()>;
+
+    /// Release a modifier
+    fn mod_release(&mut self, modifier: Modifier) -> Result<()>;
+
+    /// Toggle a modifier
+    fn mod_toggle(&mut self, modifier: Modifier) -> Result<()>;
+
+    /// Get the modifier state
+    #[must_use]
+    fn mod_query(&self, modifier: Modifier) -> bool;
+
+    /// Whether a text field is focused
+    #[must_use]
+    fn active(&self) -> bool;
+
+    /// The content type of the focused text field
+    #[must_use]
+    fn content_type(&self) -> ContentType;
+
+    /// The text around the cursor in the focused text field,
+    /// if the text field supports it
+    #[must_use]
+    fn surrounding_text(&self) -> Option<&SurroundingText>;
+
+    /// Get a receiver for events like the content type changing
+    #[must_use]
+    fn subscribe(&self) -> broadcast::Receiver<proto::Event>;
+
+    /// Get the modifiers that are currently pressed
+    #[must_use]
+    fn mods_pressed(&self) -> Vec<Modifier> {
+        [
+            Modifier::Shift,
+            Modifier::Ctrl,
+            Modifier::Alt,
+            Modifier::Cmd,
+        ]
+        .into_iter()
+        .filter(|&modifier| self.mod_query(modifier))
+        .collect()
+    }
+
+    /// Release all of the modifiers
+    fn mod_release_all(&mut self) -> Result<()>;
+
+    /// Whether the input method is available,
+    /// which is needed for auto show and text input
+    #[must_use]
+    fn has_input_method(&self) -> bool;
+
+    /// The keymap the virtual keyboard is using
+    #[must_use]
+    fn keymap_status(&self) -> proto::KeymapStatus;
+
+    /// Whether the input method can be used
+    #[must_use]
+    fn input_method_state(&self) -> proto::InputMethodState;
+
+    /// What can't be done on this compositor, and why
+    #[must_use]
+    fn missing_capabilities(&self) -> Vec<String>;
+
+    /// Get the protocols supported, with their versions and how many globals implement them
+    #[must_use]
+    fn protocols(&self) -> Vec<proto::Protocol>;
+}
+
+impl KeyboardBackend for Keyboard {
+    fn connected(&self) -> bool {
+        self.connected
+    }
+
+    fn seat_name(&self) -> String {
+        self.seat_info()
+            .map(|info| info.name.clone())
+            .unwrap_or_default()
+    }
+
+    fn seat_names(&self) -> Vec<String> {
+        self.state
+            .seats
+            .iter()
+            .map(|info| info.name.clone())
+            .collect()
+    }
+
+    fn active_seat_names(&self) -> Vec<String> {
+        self.state
+            .active_seats()
+            .into_iter()
+            .filter_map(|global| self.state.seats.iter().find(|info| info.global == global))
+            .map(|info| info.name.clone())
+            .collect()
+    }
+
+    fn set_seats(&mut self, names: &[String]) -> Result<()> {
+        self.state.chosen_seats = names.to_vec();
+        self.update_seats();
+
+        // Get the keymap from the new first seat's keyboard
+        self.roundtrip()
+    }
+
+    fn set_keymap(&mut self, keymap: &Keymap) -> Result<()> {
+        self.upload_keymap(keymap, KeymapSource::Custom)
+    }
+
+    fn reset_keymap(&mut self) -> Result<()> {
+        match self.seat_keymap.take() {
+            Some(seat_keymap) => {
+                let res = self.upload_keymap(&seat_keymap, KeymapSource::Seat);
+                self.seat_keymap = Some(seat_keymap);
+                res
+            }
+            None => self.upload_fallback_keymap(),
+        }
+    }
+
+    fn key(&mut self, key: u32, pressed: bool) -> Result<()> {
+        self.outputs()
+            .into_iter()
+            .try_for_each(|output| output.key(key, pressed))
+    }
+
+    fn text_to_keys(&self, text: &str) -> Result<Vec<(u32, bool)>> {
+        keymap::text_to_keys(&self.keymap, text)
+    }
+
+    fn commit_string(&self, text: &str) -> Result<bool> {
+        self.input_method_commit(|input_method| input_method.commit_string(text.to_string()))
+    }
+
+    fn set_preedit(&self, text: &str, cursor_begin: i32, cursor_end: i32) -> Result<bool> {
+        self.input_method_commit(|input_method| {
+            input_method.set_preedit_string(text.to_string(), cursor_begin, cursor_end);
+        })
+    }
+
+    fn delete_surrounding_text(&self, before_length: u32, after_length: u32) -> Result<bool> {
+        self.input_method_commit(|input_method| {
+            input_method.delete_surrounding_text(before_length, after_length);
+        })
+    }
+
+    fn replace_before_cursor(&self, length: u32, text: &str) -> Result<bool> {
+        // The compositor always deletes before inserting the text
+        self.input_method_commit(|input_method| {
+            input_method.delete_surrounding_text(length, 0);
+            input_method.commit_string(text.to_string());
+        })
+    }
+
+    fn auto_enable(&mut self) {
+        self.state.auto = true;
+
+        // Without the input method, the ui just stays open
+        if self.state.input_method_taken && !self.state.ui_open {
+            self.state.set_ui(true);
+        }
+    }
+
+    fn auto_disable(&mut self) {
+        self.state.auto = false;
+        self.state.hide_at = None;
+    }
+
+    fn auto_query(&self) -> bool {
+        self.state.auto
+    }
+
+    fn ui_open(&mut self) -> ui::Pending {
+        self.state.set_ui(true)
+    }
+
+    fn ui_close(&mut self) -> ui::Pending {
+        self.state.set_ui(false)
+    }
+
+    fn ui_toggle(&mut self) -> ui::Pending {
+        self.state.set_ui(!self.state.ui_open)
+    }
+
+    fn ui_query(&self) -> bool {
+        self.state.ui_open
+    }
+
+    fn mod_press(&mut self, modifier: Modifier) -> Result<()> {
+        match modifier {
+            Modifier::Shift => self.state.mods.shift = true,
+            Modifier::Ctrl => self.state.mods.ctrl = true,
+            Modifier::Alt => self.state.mods.alt = true,
+            Modifier::Cmd => self.state.mods.cmd = true,
+        }
+
+        self.send_mods()?;
+
+        Ok(())
+    }
+
+    fn mod_release(&mut self, modifier: Modifier) -> Result<()> {
+        match modifier {
+            Modifier::Shift => self.state.mods.shift = false,
+            Modifier::Ctrl => self.state.mods.ctrl = false,
+            Modifier::Alt => self.state.mods.alt = false,
+            Modifier::Cmd => self.state.mods.cmd = false,
+        }
+
+        self.send_mods()?;
+
+        Ok(())
+    }
+
+    fn mod_toggle(&mut self, modifier: Modifier) -> Result<()> {
+        match modifier {
+            Modifier::Shift => self.state.mods.shift = !self.state.mods.shift,
+            Modifier::Ctrl => self.state.mods.ctrl = !self.state.mods.ctrl,
+            Modifier::Alt => self.state.mods.alt = !self.state.mods.alt,
+            Modifier::Cmd => self.state.mods.cmd = !self.state.mods.cmd,
+        }
+
+        self.send_mods()?;
+
+        Ok(())
+    }
+
+    fn mod_query(&self, modifier: Modifier) -> bool {
+        match modifier {
+            Modifier::Shift => self.state.mods.shift,
+            Modifier::Ctrl => self.state.mods.ctrl,
+            Modifier::Alt => self.state.mods.alt,
+            Modifier::Cmd => self.state.mods.cmd,
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.state.input.active
+    }
+
+    fn content_type(&self) -> ContentType {
+        self.state.input.content_type
+    }
+
+    fn surrounding_text(&self) -> Option<&SurroundingText> {
+        self.state.input.surrounding_text.as_ref()
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<proto::Event> {
+        self.state.events.0.subscribe()
+    }
+
+    fn mod_release_all(&mut self) -> Result<()> {
+        self.state.mods = ModState::default();
+        self.send_mods()?;
+        Ok(())
+    }
+
+    fn has_input_method(&self) -> bool {
+        self.input_method.is_some()
+    }
+
+    fn keymap_status(&self) -> proto::KeymapStatus {
+        proto::KeymapStatus {
+            symbols: keymap::symbols(&self.keymap),
+            source: self.keymap_source.into(),
+        }
+    }
+
+    fn input_method_state(&self) -> proto::InputMethodState {
+        if self.input_method.is_none() {
+            proto::InputMethodState::Missing
+        } else if self.state.input_method_taken {
+            proto::InputMethodState::Taken
+        } else {
+            proto::InputMethodState::Available
+        }
+    }
+
+    fn missing_capabilities(&self) -> Vec<String> {
+        let mut missing = Vec::new();
+
+        if !self.has_input_method() {
+            missing.push(format!(
+                "auto show and text input need {}",
+                ZwpInputMethodManagerV2::interface().name
+            ));
+        }
+
+        missing
+    }
+
+    fn protocols(&self) -> Vec<proto::Protocol> {
+        self.state.registry.protocols()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::test_compositor::{self, Request, TestCompositor, SEAT_NAME};
+
+    /// A keyboard connected to a new compositor, with the setup requests cleared
+    fn setup() -> Result<(TestCompositor, Keyboard)> {
+        let compositor = TestCompositor::new()?;
+        let keyboard = compositor.keyboard()?;
+        compositor.take_requests()?;
+        Ok((compositor, keyboard))
+    }
+
+    const fn modifiers(latched: u32) -> Request {
+        Request::Modifiers {
+            depressed: 0,
+            latched,
+            locked: 0,
+        }
+    }
+
+    #[test]
+    fn copies_the_seat_keymap() -> Result<()> {
+        let compositor = TestCompositor::new()?;
+        let keyboard = compositor.keyboard()?;
+
+        assert_eq!(
+            compositor.take_requests()?,
+            [
+                Request::Keymap("pc+us+inet(evdev)".to_string()),
+                modifiers(0),
+            ]
+        );
+        assert_eq!(keyboard.keymap_status().source(), KeymapSource::Seat);
+        assert_eq!(keyboard.seat_name(), SEAT_NAME);
+        assert_eq!(
+            keyboard.input_method_state(),
+            proto::InputMethodState::Available
+        );
+        assert!(keyboard.missing_capabilities().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sends_keys() -> Result<()> {
+        let (compositor, mut keyboard) = setup()?;
+
+        keyboard.key(30, true)?;
+        keyboard.key(30, false)?;
+        keyboard.roundtrip()?;
+
+        assert_eq!(
+            compositor.take_requests()?,
+            [
+                Request::Key {
+                    key: 30,
+                    pressed: true
+                },
+                Request::Key {
+                    key: 30,
+                    pressed: false
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn latches_modifiers() -> Result<()> {
+        let (compositor, mut keyboard) = setup()?;
+
+        keyboard.mod_press(Modifier::Shift)?;
+        keyboard.mod_toggle(Modifier::Ctrl)?;
+        keyboard.mod_press(Modifier::Alt)?;
+        keyboard.mod_release(Modifier::Shift)?;
+        assert_eq!(keyboard.mods_pressed(), [Modifier::Ctrl, Modifier::Alt]);
+
+        keyboard.mod_press(Modifier::Cmd)?;
+        keyboard.mod_release_all()?;
+        assert!(keyboard.mods_pressed().is_empty());
+        keyboard.roundtrip()?;
+
+        assert_eq!(
+            compositor.take_requests()?,
+            [
+                modifiers(0x01),
+                modifiers(0x05),
+                modifiers(0x0d),
+                modifiers(0x0c),
+                modifiers(0x4c),
+                modifiers(0),
+            ]
+        );
+
+        Ok(())
+    }
+
+    /// What the mock output was sent
+    #[derive(Debug, PartialEq, Eq)]
+    enum Sent {
+        Keymap,
+        Key(u32, bool),
+        Mods(Vec<Modifier>),
+    }
+
+    /// Records what's sent instead of sending it anywhere
+    struct MockOutput(Arc<Mutex<Vec<Sent>>>);
+
+    impl MockOutput {
+        fn push(&self, sent: Sent) -> Result<()> {
+            self.0
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Poisoned"))?
+                .push(sent);
+            Ok(())
+        }
+    }
+
+    impl Output for MockOutput {
+        fn keymap(&mut self, _keymap: &Keymap) -> Result<()> {
+            self.push(Sent::Keymap)
+        }
+
+        fn key(&mut self, key: u32, pressed: bool) -> Result<()> {
+            self.push(Sent::Key(key, pressed))
+        }
+
+        fn modifiers(&mut self, mods: &[Modifier]) -> Result<()> {
+            self.push(Sent::Mods(mods.to_vec()))
+        }
+    }
+
+    #[test]
+    fn sends_to_another_output() -> Result<()> {
+        let (compositor, mut keyboard) = setup()?;
+        let sent = Arc::new(Mutex::new(Vec::new()));
+
+        keyboard.set_output(Box::new(MockOutput(sent.clone())))?;
+        keyboard.mod_press(Modifier::Shift)?;
+        keyboard.key(30, true)?;
+        keyboard.key(30, false)?;
+        keyboard.mod_release_all()?;
+        keyboard.roundtrip()?;
+
+        assert_eq!(
+            *sent.lock().map_err(|_| anyhow::anyhow!("Poisoned"))?,
+            [
+                Sent::Keymap,
+                Sent::Mods(Vec::new()),
+                Sent::Mods(vec![Modifier::Shift]),
+                Sent::Key(30, true),
+                Sent::Key(30, false),
+                Sent::Mods(Vec::new()),
+            ]
+        );
+        // Nothing goes through the compositor's virtual keyboard
+        assert_eq!(compositor.take_requests()?, []);
+
+        Ok(())
+    }
+
+    #[test]
+    fn commits_text_to_the_focused_field() -> Result<()> {
+        let (compositor, mut keyboard) = setup()?;
+
+        // Nothing is sent without a text field
+        assert!(!keyboard.commit_string("hi")?);
+
+        compositor.activate()?;
+        keyboard.roundtrip()?;
+        assert!(keyboard.active());
+
+        assert!(keyboard.commit_string("hi")?);
+        assert!(keyboard.replace_before_cursor(2, "hello ")?);
+        keyboard.roundtrip()?;
+
+        assert_eq!(
+            compositor.take_requests()?,
+            [
+                Request::CommitString("hi".to_string()),
+                Request::Commit(1),
+                Request::DeleteSurroundingText {
+                    before_length: 2,
+                    after_length: 0
+                },
+                Request::CommitString("hello ".to_string()),
+                Request::Commit(1),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn auto_shows_and_hides_the_ui() -> Result<()> {
+        test_compositor::init_ui();
+        let (compositor, mut keyboard) = setup()?;
+        keyboard.set_hide_delay(Duration::ZERO);
+
+        // With auto off, focusing a text field does nothing
+        compositor.activate()?;
+        keyboard.roundtrip()?;
+        assert!(!keyboard.ui_query());
+
+        compositor.deactivate()?;
+        keyboard.auto_enable();
+        compositor.activate()?;
+        keyboard.roundtrip()?;
+        assert!(keyboard.ui_query());
+
+        // Hiding waits for the next tick
+        compositor.deactivate()?;
+        keyboard.roundtrip()?;
+        assert!(keyboard.ui_query());
+        assert!(keyboard.next_tick().is_some());
+
+        keyboard.tick();
+        assert!(!keyboard.ui_query());
+        assert!(keyboard.next_tick().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn auto_disable_cancels_the_hide() -> Result<()> {
+        test_compositor::init_ui();
+        let (compositor, mut keyboard) = setup()?;
+        keyboard.auto_enable();
+
+        compositor.activate()?;
+        keyboard.roundtrip()?;
+        compositor.deactivate()?;
+        keyboard.roundtrip()?;
+        assert!(keyboard.next_tick().is_some());
+
+        keyboard.auto_disable();
+        assert!(keyboard.next_tick().is_none());
+        keyboard.tick();
+        assert!(keyboard.ui_query());
+
+        Ok(())
+    }
+
+    #[test]
+    fn regaining_the_input_method_hides_the_ui() -> Result<()> {
+        test_compositor::init_ui();
+        let (compositor, mut keyboard) = setup()?;
+        keyboard.set_hide_delay(Duration::ZERO);
+        keyboard.set_input_method_retry(Some(Duration::ZERO));
+        keyboard.auto_enable();
+
+        // Auto mode keeps the ui open while another IME has the input method
+        compositor.take_input_method()?;
+        keyboard.roundtrip()?;
+        assert_eq!(
+            keyboard.input_method_state(),
+            proto::InputMethodState::Taken
+        );
+        assert!(keyboard.ui_query());
+
+        // The retry gets it back, then the ui is hidden on the next tick
+        keyboard.tick();
+        assert_eq!(
+            keyboard.input_method_state(),
+            proto::InputMethodState::Available
+        );
+        assert!(keyboard.ui_query());
+
+        keyboard.tick();
+        assert!(!keyboard.ui_query());
+
+        Ok(())
+    }
+
+    #[test]
+    fn bad_keymaps_keep_the_connection() -> Result<()> {
+        let (compositor, mut keyboard) = setup()?;
+
+        // Bigger than the file, so it can't be read
+        keyboard.state.keymap = Some(Keymap {
+            fd: Keymap::from_text("")?.fd,
+            size: 1000,
+        });
+        keyboard.dispatch()?;
+
+        assert!(keyboard.connected());
+        assert_eq!(keyboard.keymap_status().symbols, "pc+us+inet(evdev)");
+
+        // Keys still work with the old keymap
+        keyboard.key(30, true)?;
+        keyboard.roundtrip()?;
+        assert_eq!(
+            compositor.take_requests()?,
+            [Request::Key {
+                key: 30,
+                pressed: true
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sends_keys_to_the_chosen_seats() -> Result<()> {
+        let compositor = TestCompositor::new()?;
+        compositor.add_seat("seat1")?;
+        let mut keyboard = compositor.keyboard()?;
+        let keymap = Request::Keymap("pc+us+inet(evdev)".to_string());
+
+        assert_eq!(compositor.virtual_keyboard_seats()?, [SEAT_NAME, "seat1"]);
+        assert_eq!(keyboard.active_seat_names(), [SEAT_NAME]);
+        // Every seat gets the keymap, but only the active one the modifiers
+        assert_eq!(
+            compositor.take_seat_requests()?,
+            [
+                (SEAT_NAME.to_string(), keymap.clone()),
+                ("seat1".to_string(), keymap),
+                (SEAT_NAME.to_string(), modifiers(0)),
+            ]
+        );
+
+        let press = |seat: &str| {
+            (
+                seat.to_string(),
+                Request::Key {
+                    key: 30,
+                    pressed: true,
+                },
+            )
+        };
+
+        keyboard.key(30, true)?;
+        keyboard.roundtrip()?;
+        assert_eq!(compositor.take_seat_requests()?, [press(SEAT_NAME)]);
+
+        // The modifiers are cleared on the seat that's no longer used
+        keyboard.set_seats(&["seat1".to_string()])?;
+        assert_eq!(compositor.input_method_seats()?, ["seat1"]);
+        keyboard.key(30, true)?;
+        keyboard.roundtrip()?;
+        assert_eq!(
+            compositor.take_seat_requests()?,
+            [
+                (SEAT_NAME.to_string(), modifiers(0)),
+                ("seat1".to_string(), modifiers(0)),
+                press("seat1"),
+            ]
+        );
+
+        keyboard.set_seats(&[SEAT_NAME.to_string(), "seat1".to_string()])?;
+        assert_eq!(keyboard.active_seat_names(), [SEAT_NAME, "seat1"]);
+        keyboard.key(30, true)?;
+        keyboard.roundtrip()?;
+        assert_eq!(
+            compositor.take_seat_requests()?,
+            [
+                (SEAT_NAME.to_string(), modifiers(0)),
+                press(SEAT_NAME),
+                press("seat1"),
+            ]
+        );
+
+        // A seat that doesn't exist yet is used once it's added
+        let seat2 = ["seat2".to_string()];
+        assert!(keyboard.check_seats(&seat2).is_err());
+        keyboard.set_seats(&seat2)?;
+        assert_eq!(keyboard.active_seat_names(), [SEAT_NAME]);
+        compositor.add_seat("seat2")?;
+        keyboard.roundtrip()?;
+        assert_eq!(keyboard.active_seat_names(), ["seat2"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn follows_added_and_removed_seats() -> Result<()> {
+        let (compositor, mut keyboard) = setup()?;
+
+        compositor.add_seat("seat1")?;
+        // The second lets the compositor see what was created for it
+        keyboard.roundtrip()?;
+        keyboard.roundtrip()?;
+        assert_eq!(compositor.virtual_keyboard_seats()?, [SEAT_NAME, "seat1"]);
+        assert_eq!(
+            compositor.take_seat_requests()?,
+            [(
+                "seat1".to_string(),
+                Request::Keymap("pc+us+inet(evdev)".to_string())
+            )]
+        );
+
+        compositor.remove_seat(SEAT_NAME)?;
+        keyboard.roundtrip()?;
+        keyboard.roundtrip()?;
+        assert_eq!(compositor.virtual_keyboard_seats()?, ["seat1"]);
+        assert_eq!(compositor.input_method_seats()?, ["seat1"]);
+        assert_eq!(keyboard.seat_name(), "seat1");
+        assert_eq!(keyboard.seat_names(), ["seat1"]);
+        assert_eq!(
+            compositor.take_seat_requests()?,
+            [("seat1".to_string(), modifiers(0))]
+        );
+
+        keyboard.key(30, true)?;
+        keyboard.roundtrip()?;
+        assert_eq!(
+            compositor.take_requests()?,
+            [Request::Key {
+                key: 30,
+                pressed: true
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn probes_without_creating_anything() -> Result<()> {
+        let compositor = TestCompositor::new()?;
+        let (registry, seats) = probe(&compositor.connect()?)?;
+
+        assert!(registry.find("zwp_virtual_keyboard_manager_v1").is_some());
+        assert_eq!(
+            seats
+                .iter()
+                .map(|seat| (seat.name.as_str(), seat.has_keyboard))
+                .collect::<Vec<_>>(),
+            [(SEAT_NAME, true)]
+        );
+        assert!(compositor.take_requests()?.is_empty());
+
+        Ok(())
     }
 }
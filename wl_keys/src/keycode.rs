@@ -1,4 +1,4 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use input_event_codes as k;
 
 /// Convert the key string to the xkb code
@@ -48,3 +48,16 @@ pub fn str_to_key(key: &str) -> Result<u32> {
         _ => bail!("Unrecognised key"),
     })
 }
+
+/// Convert a character to the xkb code with the built in US keymap,
+/// and whether shift needs to be held to type it.
+pub fn char_to_key(c: char) -> Result<(u32, bool)> {
+    let key = match c {
+        ' ' => "SPACE",
+        '\n' => "ENTER",
+        _ => &c.to_lowercase().to_string(),
+    };
+
+    let code = str_to_key(key).with_context(|| format!("Can't type {c:?} with key events"))?;
+    Ok((code, c.is_uppercase()))
+}
@@ -0,0 +1,484 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    ffi::{CStr, CString},
+    fs::File,
+    io::Write,
+    os::{fd::OwnedFd, unix::fs::FileExt},
+    path::PathBuf,
+    ptr::{self, NonNull},
+    str::FromStr,
+};
+use xkbcommon_dl::{
+    xkb_context_flags, xkb_keymap, xkb_keymap_compile_flags, xkb_keymap_format, xkb_rule_names,
+    XkbCommon,
+};
+
+use crate::{
+    keycode,
+    proto::{self, keymap_spec::Spec},
+};
+
+/// The keymap used when the seat doesn't have one, the US layout.
+///
+/// This was compiled from the `evdev` rules with the `pc105` model.
+const DEFAULT_KEYMAP: &str = include_str!("default.xkb");
+
+/// An xkb keymap in a file,
+/// this is how keymaps are sent over wayland.
+pub struct Keymap {
+    /// The file holding the keymap text
+    pub fd: OwnedFd,
+    /// The size of the file, this includes a nul terminator
+    pub size: u32,
+}
+
+impl Keymap {
+    /// Put the keymap contents in a memfd,
+    /// these should already be nul terminated.
+    pub fn new(contents: &[u8]) -> Result<Self> {
+        let fd = rustix::fs::memfd_create("wl_keys-keymap", rustix::fs::MemfdFlags::CLOEXEC)?;
+
+        let mut file = File::from(fd);
+        file.write_all(contents)?;
+
+        Ok(Self {
+            fd: file.into(),
+            size: u32::try_from(contents.len()).context("Keymap is too big")?,
+        })
+    }
+
+    /// Put the keymap text in a memfd
+    pub fn from_text(text: &str) -> Result<Self> {
+        // Compositors expect the text to be nul terminated
+        Self::new(&[text.as_bytes(), &[0]].concat())
+    }
+
+    /// Read the keymap text
+    pub fn contents(&self) -> Result<Vec<u8>> {
+        let file = File::from(self.fd.try_clone()?);
+        let mut contents = vec![0; self.size as usize];
+        // Reading at an offset leaves the fd's position alone
+        file.read_exact_at(&mut contents, 0)?;
+        Ok(contents)
+    }
+}
+
+/// The name of the keymap's symbols, like "pc+us+inet(evdev)".
+#[must_use]
+pub fn symbols(contents: &[u8]) -> String {
+    String::from_utf8_lossy(contents)
+        .lines()
+        .find_map(|line| {
+            line.trim()
+                .strip_prefix("xkb_symbols")?
+                .split('"')
+                .nth(1)
+                .map(ToString::to_string)
+        })
+        .unwrap_or_default()
+}
+
+/// The names xkb uses to build a keymap,
+/// any left empty use the system defaults.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct RuleNames {
+    /// Like "evdev"
+    pub rules: String,
+    /// Like "pc105"
+    pub model: String,
+    /// Like "us,de"
+    pub layout: String,
+    /// Like ",nodeadkeys"
+    pub variant: String,
+    /// Like "caps:escape"
+    pub options: String,
+}
+
+/// Where to get a keymap from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeymapSpec {
+    /// The US keymap built into `wl_keys`
+    Default,
+    /// An xkb keymap file
+    File(PathBuf),
+    /// Compiled by libxkbcommon
+    Names(RuleNames),
+}
+
+impl FromStr for KeymapSpec {
+    type Err = String;
+
+    /// Parse either:
+    /// - "default" for the built in keymap
+    /// - a path to an xkb file
+    /// - "key=value" pairs separated by ';', where the keys are
+    ///   rules, model, layout, variant, and options
+    /// - just a layout, which can't look like a path
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        if spec.is_empty() {
+            return Err("The keymap can't be empty".to_string());
+        }
+
+        if spec == "default" {
+            return Ok(Self::Default);
+        }
+
+        if !spec.contains('=') {
+            let path = PathBuf::from(spec);
+            if path.is_file() || path.extension().is_some_and(|ext| ext == "xkb") {
+                return Ok(Self::File(path));
+            }
+
+            // Layout names never have these, so it's a mistyped path
+            if spec.contains(['/', '.']) {
+                return Err(format!("There's no keymap file at {spec}"));
+            }
+
+            return Ok(Self::Names(RuleNames {
+                layout: spec.to_string(),
+                ..RuleNames::default()
+            }));
+        }
+
+        let mut names = RuleNames::default();
+
+        for pair in spec.split(';').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got {pair:?}"))?;
+            let value = value.to_string();
+
+            match key.trim() {
+                "rules" => names.rules = value,
+                "model" => names.model = value,
+                "layout" => names.layout = value,
+                "variant" => names.variant = value,
+                "options" => names.options = value,
+                key => return Err(format!("Unknown keymap key {key:?}")),
+            }
+        }
+
+        Ok(Self::Names(names))
+    }
+}
+
+impl From<KeymapSpec> for proto::KeymapSpec {
+    fn from(spec: KeymapSpec) -> Self {
+        let spec = match spec {
+            KeymapSpec::Default => Spec::Builtin(()),
+            KeymapSpec::File(path) => Spec::File(path.to_string_lossy().into_owned()),
+            KeymapSpec::Names(names) => Spec::Names(proto::RuleNames {
+                rules: names.rules,
+                model: names.model,
+                layout: names.layout,
+                variant: names.variant,
+                options: names.options,
+            }),
+        };
+
+        Self { spec: Some(spec) }
+    }
+}
+
+impl TryFrom<proto::KeymapSpec> for KeymapSpec {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: proto::KeymapSpec) -> Result<Self> {
+        Ok(match spec.spec.context("No keymap given")? {
+            Spec::Builtin(()) => Self::Default,
+            Spec::File(path) => Self::File(PathBuf::from(path)),
+            Spec::Names(names) => Self::Names(RuleNames {
+                rules: names.rules,
+                model: names.model,
+                layout: names.layout,
+                variant: names.variant,
+                options: names.options,
+            }),
+        })
+    }
+}
+
+impl KeymapSpec {
+    /// Get the keymap text
+    pub fn text(&self) -> Result<String> {
+        match self {
+            Self::Default => Ok(DEFAULT_KEYMAP.to_string()),
+            Self::File(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read keymap {}", path.display()))?;
+                check(&text).with_context(|| format!("Keymap {} is invalid", path.display()))?;
+                Ok(text)
+            }
+            Self::Names(names) => compile(names),
+        }
+    }
+}
+
+fn xkbcommon() -> Result<&'static XkbCommon> {
+    xkbcommon_dl::xkbcommon_option()
+        .context("libxkbcommon couldn't be loaded, it's needed to compile keymaps")
+}
+
+/// What to compile a keymap from
+enum Source<'a> {
+    /// The keymap's text
+    Text(&'a CStr),
+    /// The names for xkb to build it from
+    Names(&'a xkb_rule_names),
+}
+
+/// A keymap compiled by libxkbcommon, unref'd when it's dropped
+struct Compiled<'a> {
+    xkb: &'a XkbCommon,
+    keymap: NonNull<xkb_keymap>,
+}
+
+impl<'a> Compiled<'a> {
+    /// Compile a keymap, failing if it's invalid
+    fn new(xkb: &'a XkbCommon, source: &Source<'_>) -> Result<Self> {
+        // SAFETY: The context is checked before use and unref'd after,
+        // which the keymap keeps its own reference to,
+        // and the text or names live for the whole call.
+        let keymap = unsafe {
+            let context = (xkb.xkb_context_new)(xkb_context_flags::XKB_CONTEXT_NO_FLAGS);
+            if context.is_null() {
+                bail!("Failed to create the xkb context");
+            }
+
+            let keymap = match source {
+                Source::Text(text) => (xkb.xkb_keymap_new_from_string)(
+                    context,
+                    text.as_ptr(),
+                    xkb_keymap_format::XKB_KEYMAP_FORMAT_TEXT_V1,
+                    xkb_keymap_compile_flags::XKB_KEYMAP_COMPILE_NO_FLAGS,
+                ),
+                Source::Names(names) => (xkb.xkb_keymap_new_from_names)(
+                    context,
+                    ptr::from_ref(*names),
+                    xkb_keymap_compile_flags::XKB_KEYMAP_COMPILE_NO_FLAGS,
+                ),
+            };
+            (xkb.xkb_context_unref)(context);
+            keymap
+        };
+
+        let keymap = NonNull::new(keymap).context("The keymap failed to compile")?;
+        Ok(Self { xkb, keymap })
+    }
+
+    /// The keymap for libxkbcommon calls, valid while this is alive
+    const fn as_ptr(&self) -> *mut xkb_keymap {
+        self.keymap.as_ptr()
+    }
+}
+
+impl Drop for Compiled<'_> {
+    fn drop(&mut self) {
+        // SAFETY: This holds the only reference, and it isn't used after this
+        unsafe { (self.xkb.xkb_keymap_unref)(self.keymap.as_ptr()) };
+    }
+}
+
+/// Make sure a keymap file will compile before sending it to the compositor.
+///
+/// If libxkbcommon isn't installed this is skipped.
+fn check(text: &str) -> Result<()> {
+    let Ok(xkb) = xkbcommon() else {
+        return Ok(());
+    };
+
+    let text = CString::new(text).context("Keymap has a nul in it")?;
+    Compiled::new(xkb, &Source::Text(&text))?;
+
+    Ok(())
+}
+
+/// Find the keys that type `text` with a keymap,
+/// as their evdev codes and whether shift needs to be held.
+///
+/// Only the first layout is searched, and only the levels reached with
+/// no modifiers or with shift, since those are all the virtual keyboard can use.
+///
+/// Without libxkbcommon only the built in keymap can be used.
+pub fn text_to_keys(contents: &[u8], text: &str) -> Result<Vec<(u32, bool)>> {
+    let contents = contents.strip_suffix(&[0]).unwrap_or(contents);
+
+    let Ok(xkb) = xkbcommon() else {
+        if contents == DEFAULT_KEYMAP.as_bytes() {
+            return text.chars().map(keycode::char_to_key).collect();
+        }
+
+        bail!("libxkbcommon couldn't be loaded, it's needed to type with this keymap");
+    };
+
+    let keymap_text = CString::new(contents).context("Keymap has a nul in it")?;
+    let keymap = Compiled::new(xkb, &Source::Text(&keymap_text))?;
+
+    text.chars()
+        .map(|c| {
+            find_key(&keymap, c).with_context(|| {
+                format!("Can't type {c:?} with the keymap {:?}", symbols(contents))
+            })
+        })
+        .collect()
+}
+
+/// Find the key in a compiled keymap that types `c`,
+/// preferring keys that don't need shift.
+fn find_key(keymap: &Compiled<'_>, c: char) -> Option<(u32, bool)> {
+    // Return types a carriage return
+    let c = if c == '\n' { '\r' } else { c };
+    let xkb = keymap.xkb;
+    let keymap = keymap.as_ptr();
+
+    // SAFETY: The keymap is valid, and the keysyms it returns
+    // are only read while it's alive.
+    unsafe {
+        let min = (xkb.xkb_keymap_min_keycode)(keymap);
+        let max = (xkb.xkb_keymap_max_keycode)(keymap);
+
+        // With the usual key types level 1 is reached with shift
+        (0..2).find_map(|level| {
+            (min..=max).find_map(|code| {
+                let mut syms = ptr::null();
+                let len =
+                    (xkb.xkb_keymap_key_get_syms_by_level)(keymap, code, 0, level, &raw mut syms);
+                if len <= 0 || syms.is_null() {
+                    return None;
+                }
+
+                std::slice::from_raw_parts(syms, len as usize)
+                    .iter()
+                    .any(|&sym| (xkb.xkb_keysym_to_utf32)(sym) == u32::from(c))
+                    // xkb keycodes are evdev codes offset by 8
+                    .then(|| code.checked_sub(8).map(|key| (key, level == 1)))
+                    .flatten()
+            })
+        })
+    }
+}
+
+/// Compile a keymap from its names with libxkbcommon
+fn compile(names: &RuleNames) -> Result<String> {
+    let xkb = xkbcommon()?;
+
+    let to_c = |name: &str| CString::new(name).context("Keymap name has a nul in it");
+    let rules = to_c(&names.rules)?;
+    let model = to_c(&names.model)?;
+    let layout = to_c(&names.layout)?;
+    let variant = to_c(&names.variant)?;
+    let options = to_c(&names.options)?;
+
+    // Null pointers make xkb use the defaults
+    let or_null = |name: &CString| {
+        if name.is_empty() {
+            ptr::null()
+        } else {
+            name.as_ptr()
+        }
+    };
+    let raw_names = xkb_rule_names {
+        rules: or_null(&rules),
+        model: or_null(&model),
+        layout: or_null(&layout),
+        variant: or_null(&variant),
+        options: or_null(&options),
+    };
+
+    let keymap = Compiled::new(xkb, &Source::Names(&raw_names))
+        .with_context(|| format!("Failed to compile the keymap {names:?}"))?;
+
+    keymap_to_string(&keymap)
+}
+
+/// Get the text of a compiled keymap
+fn keymap_to_string(keymap: &Compiled<'_>) -> Result<String> {
+    // SAFETY: The keymap is valid, and the returned string is
+    // copied before it's freed, as libxkbcommon says to.
+    unsafe {
+        let raw = (keymap.xkb.xkb_keymap_get_as_string)(
+            keymap.as_ptr(),
+            xkb_keymap_format::XKB_KEYMAP_FORMAT_TEXT_V1,
+        );
+
+        if raw.is_null() {
+            bail!("Failed to get the keymap as a string");
+        }
+
+        let text = CStr::from_ptr(raw).to_string_lossy().into_owned();
+        libc::free(raw.cast_mut().cast());
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn parses_keymap_specs() {
+        assert_eq!("default".parse(), Ok(KeymapSpec::Default));
+        assert_eq!(
+            "us.xkb".parse(),
+            Ok(KeymapSpec::File(PathBuf::from("us.xkb")))
+        );
+        assert_eq!(
+            "de".parse(),
+            Ok(KeymapSpec::Names(RuleNames {
+                layout: "de".to_string(),
+                ..RuleNames::default()
+            }))
+        );
+        assert_eq!(
+            "layout=us,de;variant=dvorak;options=caps:escape;".parse(),
+            Ok(KeymapSpec::Names(RuleNames {
+                layout: "us,de".to_string(),
+                variant: "dvorak".to_string(),
+                options: "caps:escape".to_string(),
+                ..RuleNames::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn existing_files_are_keymap_files() {
+        let path = std::env::current_exe().ok();
+        let path = path.as_deref().and_then(Path::to_str).unwrap_or_default();
+
+        assert_eq!(path.parse(), Ok(KeymapSpec::File(PathBuf::from(path))));
+    }
+
+    #[test]
+    fn invalid_keymap_specs_fail() {
+        assert!("".parse::<KeymapSpec>().is_err());
+        assert!("layout=us;colour=red".parse::<KeymapSpec>().is_err());
+        assert!("layout=us;model".parse::<KeymapSpec>().is_err());
+        // Mistyped paths aren't taken as layouts
+        assert!("~/keymaps/us".parse::<KeymapSpec>().is_err());
+        assert!("keymap.txt".parse::<KeymapSpec>().is_err());
+    }
+
+    #[test]
+    fn types_with_the_keymap() -> Result<()> {
+        let keymap = Keymap::from_text(DEFAULT_KEYMAP)?;
+
+        assert_eq!(
+            text_to_keys(&keymap.contents()?, "aA 1\n")?,
+            [
+                (30, false),
+                (30, true),
+                (57, false),
+                (2, false),
+                (28, false)
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn cant_type_chars_missing_from_the_keymap() {
+        assert!(text_to_keys(DEFAULT_KEYMAP.as_bytes(), "aä").is_err());
+    }
+}
@@ -21,110 +21,434 @@
 #![allow(clippy::module_name_repetitions)]
 // I think this lint is falsely triggering
 #![allow(clippy::significant_drop_tightening)]
+// tonic's Status is big, but it's what the grpc traits return
+#![allow(clippy::result_large_err)]
 
 /// The program arguments with clap
 pub mod args;
 /// Values like the socket file location.
 pub mod config;
+/// The content type of the focused text field
+pub mod content;
 /// The daemon.
 pub mod daemon;
+/// Checks the compositor and config for problems
+pub mod doctor;
 /// The actual virtual keyboard that connects to wayland.
 pub mod keyboard;
 /// Converts the key string to the xkb code
 pub mod keycode;
+/// Loads and compiles xkb keymaps
+pub mod keymap;
+/// Where the key events are sent, the compositor or /dev/uinput
+pub mod output;
+/// Word suggestions
+pub mod predict;
+/// Tracks the globals advertised by the compositor
+pub mod registry;
+/// A compositor to run the keyboard against in tests
+#[cfg(test)]
+pub mod test_compositor;
 /// Manages the eww UI
 pub mod ui;
 
 use anyhow::{Context, Result};
-use args::{AutoCmd, Command, DaemonCmd, ModCmd, UiCmd};
+use args::{
+    AutoCmd, Command, DaemonCmd, KeymapCmd, ModCmd, PreeditCmd, SeatCmd, SuggestCmd,
+    SurroundingCmd, UiCmd,
+};
 use clap::Parser;
+use content::ContentType;
 use daemon::client;
 use keycode::str_to_key;
+use keymap::KeymapSpec;
 use proto::{ModMsg, Modifier};
+use std::fmt::Write;
 pub use wl_keys_proto as proto;
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-async fn main() -> Result<()> {
-    let args = Command::parse();
+fn parse_mod(mod_str: &str) -> Result<ModMsg> {
+    Ok(ModMsg {
+        modifier: Modifier::from_str_name(mod_str)
+            .context("Invalid modifier")?
+            .into(),
+    })
+}
 
-    match args {
-        Command::Daemon(cmd) => match cmd {
-            DaemonCmd::Start => daemon::daemon().await?,
-            DaemonCmd::Stop => {
-                client().await?.stop(()).await?;
-            }
-            DaemonCmd::Protocols => {
-                let protocols = client().await?.get_protocols(()).await?;
-                for protocol in &protocols.get_ref().protocols {
-                    println!("{protocol}");
-                }
+fn content_type_str(content_type: &proto::ContentType) -> String {
+    let content_type = ContentType::new(content_type.hint, content_type.purpose as u32);
+    let hints = content_type
+        .hints()
+        .map(|hint| hint.as_str_name())
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{} {hints}", content_type.purpose.as_str_name())
+}
+
+fn protocol_str(protocol: &proto::Protocol) -> String {
+    let names = protocol
+        .names
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let used = if protocol.used {
+        format!("used v{}", protocol.bound_version)
+    } else {
+        "unused".to_string()
+    };
+
+    format!(
+        "{:<44} v{:<3} x{:<3} names {names:<8} {used}",
+        protocol.interface, protocol.version, protocol.count
+    )
+}
+
+/// Quote a string for JSON
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", u32::from(c));
             }
-        },
-        Command::Auto(cmd) => match cmd {
-            AutoCmd::Enable => {
-                client().await?.auto_enable(()).await?;
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Format the suggestions as a JSON array for eww,
+/// each with its index so it can be accepted without quoting the word
+fn json_suggestions(words: &[String]) -> String {
+    let words = words
+        .iter()
+        .enumerate()
+        .map(|(index, word)| format!("{{\"index\":{index},\"word\":{}}}", json_string(word)))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!("[{words}]")
+}
+
+fn keymap_str(keymap: &proto::KeymapStatus) -> String {
+    format!("{} {}", keymap.source().as_str_name(), keymap.symbols)
+}
+
+fn print_event(event: proto::event::Event) {
+    match event {
+        proto::event::Event::Ui(status) => println!("ui {}", status.open),
+        proto::event::Event::ContentType(content_type) => {
+            println!("content_type {}", content_type_str(&content_type));
+        }
+        proto::event::Event::InputMethod(state) => {
+            let state = proto::InputMethodState::try_from(state).unwrap_or_default();
+            println!("input_method {}", state.as_str_name());
+        }
+        proto::event::Event::Keymap(keymap) => println!("keymap {}", keymap_str(&keymap)),
+        proto::event::Event::SurroundingText(surrounding_text) => {
+            println!(
+                "surrounding_text {} {} {:?}",
+                surrounding_text.cursor, surrounding_text.anchor, surrounding_text.text
+            );
+        }
+    }
+}
+
+async fn daemon_cmd(cmd: DaemonCmd) -> Result<()> {
+    match cmd {
+        DaemonCmd::Start(args) => daemon::daemon(&args).await?,
+        DaemonCmd::Doctor(args) => doctor::doctor(&args).await?,
+        DaemonCmd::Stop => {
+            client().await?.stop(()).await?;
+        }
+        DaemonCmd::Protocols => {
+            let protocols = client().await?.get_protocols(()).await?;
+            for protocol in &protocols.get_ref().protocols {
+                println!("{}", protocol_str(protocol));
             }
-            AutoCmd::Disable => {
-                client().await?.auto_disable(()).await?;
+            for missing in &protocols.get_ref().missing {
+                eprintln!("missing: {missing}");
             }
-            AutoCmd::Toggle => {
-                client().await?.auto_toggle(()).await?;
+        }
+        DaemonCmd::Status => {
+            let status = client().await?.get_status(()).await?.into_inner();
+            let mods = status
+                .mods()
+                .map(|modifier| modifier.as_str_name())
+                .collect::<Vec<_>>()
+                .join(" ");
+            println!("auto: {}", status.auto);
+            println!("ui: {}", if status.ui_open { "open" } else { "closed" });
+            println!("mods: {mods}");
+            println!("active: {}", status.active);
+            if let Some(content_type) = &status.content_type {
+                println!("content: {}", content_type_str(content_type));
             }
-            AutoCmd::Query => {
-                let enabled = client().await?.auto_query(()).await?.get_ref().enabled;
-                println!("{enabled}");
+            println!("layer: {}", status.layer);
+            println!("suggestions: {}", status.suggestions);
+            println!("input method: {}", status.input_method().as_str_name());
+            if let Some(keymap) = &status.keymap {
+                println!("keymap: {}", keymap_str(keymap));
             }
-        },
-        Command::Ui(cmd) => match cmd {
-            UiCmd::Open => ui::open()?,
-            UiCmd::Close => ui::close()?,
-            UiCmd::Toggle => ui::toggle()?,
-        },
-        Command::Mod(cmd) => match cmd {
-            ModCmd::Press { modifier: mod_str } => {
-                client()
-                    .await?
-                    .mod_press(ModMsg {
-                        modifier: Modifier::from_str_name(&mod_str)
-                            .context("Invalid modifier")?
-                            .into(),
-                    })
-                    .await?;
+            println!("seat: {}", status.seat);
+            println!("seats: {}", status.seats.join(" "));
+            println!("active seats: {}", status.active_seats.join(" "));
+            for missing in &status.missing {
+                println!("missing: {missing}");
             }
-            ModCmd::Release { modifier: mod_str } => {
-                client()
-                    .await?
-                    .mod_release(ModMsg {
-                        modifier: Modifier::from_str_name(&mod_str)
-                            .context("Invalid modifier")?
-                            .into(),
-                    })
-                    .await?;
+        }
+        DaemonCmd::Events => {
+            let mut events = client().await?.subscribe(()).await?.into_inner();
+            while let Some(event) = events.message().await? {
+                if let Some(event) = event.event {
+                    print_event(event);
+                }
             }
-            ModCmd::Toggle { modifier: mod_str } => {
-                client()
-                    .await?
-                    .mod_toggle(ModMsg {
-                        modifier: Modifier::from_str_name(&mod_str)
-                            .context("Invalid modifier")?
-                            .into(),
-                    })
-                    .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn auto_cmd(cmd: AutoCmd) -> Result<()> {
+    match cmd {
+        AutoCmd::Enable => {
+            client().await?.auto_enable(()).await?;
+        }
+        AutoCmd::Disable => {
+            client().await?.auto_disable(()).await?;
+        }
+        AutoCmd::Toggle => {
+            client().await?.auto_toggle(()).await?;
+        }
+        AutoCmd::Query => {
+            let enabled = client().await?.auto_query(()).await?.get_ref().enabled;
+            println!("{enabled}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn ui_cmd(cmd: UiCmd) -> Result<()> {
+    match cmd {
+        UiCmd::Open => {
+            client().await?.ui_open(()).await?;
+        }
+        UiCmd::Close => {
+            client().await?.ui_close(()).await?;
+        }
+        UiCmd::Toggle => {
+            client().await?.ui_toggle(()).await?;
+        }
+        UiCmd::Query => {
+            let open = client().await?.ui_query(()).await?.get_ref().open;
+            println!("{open}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn mod_cmd(cmd: ModCmd) -> Result<()> {
+    match cmd {
+        ModCmd::Press { modifier: mod_str } => {
+            client().await?.mod_press(parse_mod(&mod_str)?).await?;
+        }
+        ModCmd::Release { modifier: mod_str } => {
+            client().await?.mod_release(parse_mod(&mod_str)?).await?;
+        }
+        ModCmd::Toggle { modifier: mod_str } => {
+            client().await?.mod_toggle(parse_mod(&mod_str)?).await?;
+        }
+        ModCmd::Query { modifier: mod_str } => {
+            let pressed = client()
+                .await?
+                .mod_query(parse_mod(&mod_str)?)
+                .await?
+                .get_ref()
+                .pressed;
+            println!("{pressed}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn preedit_cmd(cmd: PreeditCmd) -> Result<()> {
+    match cmd {
+        PreeditCmd::Set {
+            text,
+            cursor_begin,
+            cursor_end,
+        } => {
+            let cursor_begin = match cursor_begin {
+                Some(cursor_begin) => cursor_begin,
+                None => i32::try_from(text.len()).context("Text is too long")?,
+            };
+            let cursor_end = cursor_end.unwrap_or(cursor_begin);
+
+            client()
+                .await?
+                .set_preedit(proto::Preedit {
+                    text,
+                    cursor_begin,
+                    cursor_end,
+                })
+                .await?;
+        }
+        PreeditCmd::Clear => {
+            client().await?.clear_preedit(()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn surrounding_cmd(cmd: SurroundingCmd) -> Result<()> {
+    match cmd {
+        SurroundingCmd::Get => {
+            let surrounding_text = client().await?.get_surrounding_text(()).await?.into_inner();
+            println!("text: {:?}", surrounding_text.text);
+            println!("cursor: {}", surrounding_text.cursor);
+            println!("anchor: {}", surrounding_text.anchor);
+        }
+        SurroundingCmd::Delete { before, after } => {
+            client()
+                .await?
+                .delete_surrounding_text(proto::DeleteSurrounding {
+                    before_length: before,
+                    after_length: after,
+                })
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn suggest_cmd(cmd: SuggestCmd) -> Result<()> {
+    match cmd {
+        SuggestCmd::Get { limit, json } => {
+            let res = client()
+                .await?
+                .get_suggestions(proto::SuggestionsRequest {
+                    limit: limit.unwrap_or(0),
+                })
+                .await;
+
+            let words = match res {
+                Ok(suggestions) => suggestions.into_inner().words,
+                // eww polls this, so no text field isn't an error
+                Err(status) if json && status.code() == tonic::Code::FailedPrecondition => {
+                    Vec::new()
+                }
+                Err(status) => return Err(status.into()),
+            };
+
+            if json {
+                println!("{}", json_suggestions(&words));
+            } else {
+                for word in words {
+                    println!("{word}");
+                }
             }
-            ModCmd::Query { modifier: mod_str } => {
-                let pressed = client()
-                    .await?
-                    .mod_query(ModMsg {
-                        modifier: Modifier::from_str_name(&mod_str)
-                            .context("Invalid modifier")?
-                            .into(),
-                    })
-                    .await?
-                    .get_ref()
-                    .pressed;
-                println!("{pressed}");
+        }
+        SuggestCmd::Accept { word, index } => {
+            let mut client = client().await?;
+
+            let word = match (word, index) {
+                (Some(word), _) => word,
+                // clap requires one of them, and the same order as `get`
+                // is used so the index picks the word that was shown
+                (None, index) => {
+                    let index = index.unwrap_or_default();
+                    client
+                        .get_suggestions(proto::SuggestionsRequest { limit: index + 1 })
+                        .await?
+                        .into_inner()
+                        .words
+                        .into_iter()
+                        .nth(index as usize)
+                        .with_context(|| format!("There's no suggestion {index}"))?
+                }
+            };
+
+            client.accept_suggestion(proto::Text { text: word }).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn keymap_cmd(cmd: KeymapCmd) -> Result<()> {
+    let status = match cmd {
+        KeymapCmd::Set { keymap } => {
+            // The daemon reads the file, so it can't be relative to here
+            let keymap = match keymap {
+                KeymapSpec::File(path) => KeymapSpec::File(std::path::absolute(path)?),
+                keymap => keymap,
+            };
+
+            client()
+                .await?
+                .set_keymap(proto::KeymapSpec::from(keymap))
+                .await?
+        }
+        KeymapCmd::Reset => client().await?.reset_keymap(()).await?,
+        KeymapCmd::Get => client().await?.get_keymap(()).await?,
+    };
+
+    println!("{}", keymap_str(status.get_ref()));
+
+    Ok(())
+}
+
+async fn seat_cmd(cmd: SeatCmd) -> Result<()> {
+    let names = match cmd {
+        SeatCmd::Set { names } => names,
+        SeatCmd::Reset => Vec::new(),
+        SeatCmd::List => {
+            let status = client().await?.get_status(()).await?.into_inner();
+            for seat in &status.seats {
+                let active = if status.active_seats.contains(seat) {
+                    " *"
+                } else {
+                    ""
+                };
+                println!("{seat}{active}");
             }
-        },
+            return Ok(());
+        }
+    };
+
+    let active = client()
+        .await?
+        .set_seats(proto::Seats { names })
+        .await?
+        .into_inner();
+    println!("{}", active.names.join(" "));
+
+    Ok(())
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
+async fn main() -> Result<()> {
+    let args = Command::parse();
+
+    match args {
+        Command::Daemon(cmd) => daemon_cmd(cmd).await?,
+        Command::Auto(cmd) => auto_cmd(cmd).await?,
+        Command::Ui(cmd) => ui_cmd(cmd).await?,
+        Command::Mod(cmd) => mod_cmd(cmd).await?,
+        Command::Preedit(cmd) => preedit_cmd(cmd).await?,
+        Command::Surrounding(cmd) => surrounding_cmd(cmd).await?,
+        Command::Suggest(cmd) => suggest_cmd(cmd).await?,
+        Command::Keymap(cmd) => keymap_cmd(cmd).await?,
+        Command::Seat(cmd) => seat_cmd(cmd).await?,
         Command::Key { key: key_str } => {
             client()
                 .await?
@@ -133,7 +457,10 @@ async fn main() -> Result<()> {
                 })
                 .await?;
         }
-    };
+        Command::Commit { text } => {
+            client().await?.commit_string(proto::Text { text }).await?;
+        }
+    }
 
     Ok(())
 }
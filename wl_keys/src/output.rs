@@ -0,0 +1,263 @@
+use anyhow::Result;
+use std::os::fd::AsFd;
+use wayland_client::{protocol::wl_keyboard, Connection};
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1;
+
+use crate::{keymap::Keymap, proto::Modifier};
+
+/// Where to send the key events
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputKind {
+    /// The compositor's virtual keyboard protocol
+    #[default]
+    Wayland,
+    /// A kernel virtual input device made through /dev/uinput,
+    /// for compositors without the virtual keyboard protocol.
+    /// The compositor types its keys with its own keymap, not the one set here
+    #[cfg(feature = "uinput")]
+    Uinput,
+}
+
+/// Sends the key and modifier events for the `Keyboard`
+pub trait Output: Send {
+    /// Use this keymap for the keys sent after,
+    /// the modifiers are sent again after this
+    fn keymap(&mut self, keymap: &Keymap) -> Result<()>;
+
+    /// Set the state of a key
+    fn key(&mut self, key: u32, pressed: bool) -> Result<()>;
+
+    /// Set the modifiers applied to the keys sent after
+    fn modifiers(&mut self, mods: &[Modifier]) -> Result<()>;
+}
+
+/// Sends keys through the compositor with `zwp_virtual_keyboard_v1`
+pub struct WaylandOutput {
+    virtual_kbd: ZwpVirtualKeyboardV1,
+    conn: Connection,
+    /// The time of the last key event, in milliseconds
+    last_time: u32,
+}
+
+impl WaylandOutput {
+    /// Send keys with `virtual_kbd`, flushing `conn` after each request
+    #[must_use]
+    pub const fn new(virtual_kbd: ZwpVirtualKeyboardV1, conn: Connection) -> Self {
+        Self {
+            virtual_kbd,
+            conn,
+            last_time: 0,
+        }
+    }
+
+    /// The time for the next key event.
+    ///
+    /// Each is at least a millisecond after the last,
+    /// so a press and release sent together aren't at the same time.
+    fn next_time(&mut self) -> u32 {
+        let now = time();
+        self.last_time = if now > self.last_time {
+            now
+        } else {
+            self.last_time.wrapping_add(1)
+        };
+        self.last_time
+    }
+}
+
+fn time() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or(std::time::Duration::from_millis(0))
+        .as_millis() as u32
+}
+
+/// The bit for the modifier in the keymap's modifier mask
+const fn mod_mask(modifier: Modifier) -> u32 {
+    // These values are set by the keymap,
+    // so I found these out with 'wev' on my real kbd
+    match modifier {
+        Modifier::Shift => 0x01,
+        Modifier::Ctrl => 0x04,
+        Modifier::Alt => 0x08,
+        Modifier::Cmd => 0x40,
+    }
+}
+
+impl Output for WaylandOutput {
+    fn keymap(&mut self, keymap: &Keymap) -> Result<()> {
+        self.virtual_kbd.keymap(
+            wl_keyboard::KeymapFormat::XkbV1.into(),
+            keymap.fd.as_fd(),
+            keymap.size,
+        );
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    fn key(&mut self, key: u32, pressed: bool) -> Result<()> {
+        let time = self.next_time();
+        self.virtual_kbd.key(time, key, pressed.into());
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    fn modifiers(&mut self, mods: &[Modifier]) -> Result<()> {
+        let latched = mods
+            .iter()
+            .fold(0, |latched, &modifier| latched | mod_mask(modifier));
+
+        self.virtual_kbd.modifiers(0, latched, 0, 0);
+        self.conn.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for WaylandOutput {
+    fn drop(&mut self) {
+        // This does nothing if the connection was lost
+        self.virtual_kbd.destroy();
+    }
+}
+
+/// Sends keys through a kernel virtual input device,
+/// which the compositor treats like any other keyboard.
+///
+/// The compositor uses its own keymap for the device,
+/// so the keymap given here is ignored, and modifiers are held down as keys.
+#[cfg(feature = "uinput")]
+pub struct UinputOutput {
+    device: evdev::uinput::VirtualDevice,
+    /// The modifier keys being held down
+    mods: Vec<Modifier>,
+}
+
+/// The device has the keys below this, the mouse and joystick buttons,
+/// which covers all the keys on a keyboard
+#[cfg(feature = "uinput")]
+const LAST_KEY: u16 = evdev::KeyCode::BTN_0.code();
+
+/// The evdev keycode as one the device has
+#[cfg(feature = "uinput")]
+fn uinput_key(key: u32) -> Result<u16> {
+    use anyhow::Context;
+
+    u16::try_from(key)
+        .ok()
+        .filter(|key| (1..LAST_KEY).contains(key))
+        .with_context(|| format!("Key {key} can't be sent through uinput, it isn't a keyboard key"))
+}
+
+#[cfg(feature = "uinput")]
+impl UinputOutput {
+    /// Create the virtual input device
+    pub fn new() -> Result<Self> {
+        use anyhow::Context;
+        use evdev::{uinput::VirtualDevice, AttributeSet, KeyCode};
+
+        let keys = (1..LAST_KEY).map(KeyCode).collect::<AttributeSet<_>>();
+
+        let device = VirtualDevice::builder()
+            .context(
+                "Failed to open /dev/uinput, is the uinput module loaded and are you in the input group?",
+            )?
+            .name("wl_keys")
+            .with_keys(&keys)?
+            .build()?;
+
+        Ok(Self {
+            device,
+            mods: Vec::new(),
+        })
+    }
+
+    fn key_event(key: u16, pressed: bool) -> evdev::InputEvent {
+        *evdev::KeyEvent::new(evdev::KeyCode(key), i32::from(pressed))
+    }
+
+    /// The events to go from holding the `held` modifiers to `mods`
+    fn mod_events(held: &[Modifier], mods: &[Modifier]) -> Vec<evdev::InputEvent> {
+        let released = held
+            .iter()
+            .filter(|modifier| !mods.contains(modifier))
+            .map(|&modifier| Self::key_event(Self::mod_key(modifier), false));
+        let pressed = mods
+            .iter()
+            .filter(|modifier| !held.contains(modifier))
+            .map(|&modifier| Self::key_event(Self::mod_key(modifier), true));
+        released.chain(pressed).collect()
+    }
+
+    /// The key to hold down for the modifier
+    const fn mod_key(modifier: Modifier) -> u16 {
+        match modifier {
+            Modifier::Shift => evdev::KeyCode::KEY_LEFTSHIFT.code(),
+            Modifier::Ctrl => evdev::KeyCode::KEY_LEFTCTRL.code(),
+            Modifier::Alt => evdev::KeyCode::KEY_LEFTALT.code(),
+            Modifier::Cmd => evdev::KeyCode::KEY_LEFTMETA.code(),
+        }
+    }
+}
+
+#[cfg(feature = "uinput")]
+impl Output for UinputOutput {
+    fn keymap(&mut self, _keymap: &Keymap) -> Result<()> {
+        Ok(())
+    }
+
+    fn key(&mut self, key: u32, pressed: bool) -> Result<()> {
+        self.device
+            .emit(&[Self::key_event(uinput_key(key)?, pressed)])?;
+        Ok(())
+    }
+
+    fn modifiers(&mut self, mods: &[Modifier]) -> Result<()> {
+        let events = Self::mod_events(&self.mods, mods);
+
+        if !events.is_empty() {
+            self.device.emit(&events)?;
+        }
+        self.mods = mods.to_vec();
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "uinput"))]
+mod tests {
+    use evdev::{InputEvent, KeyCode};
+
+    use super::*;
+
+    #[test]
+    fn only_sends_keyboard_keys() {
+        assert_eq!(uinput_key(30).ok(), Some(30));
+        assert!(uinput_key(0).is_err());
+        assert!(uinput_key(u32::from(KeyCode::BTN_0.code())).is_err());
+        // Would be KEY_A if it was cut down to a u16
+        assert!(uinput_key(65536 + 30).is_err());
+    }
+
+    #[test]
+    fn holds_modifiers_as_keys() {
+        let event = |key: KeyCode, pressed| UinputOutput::key_event(key.code(), pressed);
+        let codes = |events: Vec<InputEvent>| {
+            events
+                .into_iter()
+                .map(|event| (event.code(), event.value()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            codes(UinputOutput::mod_events(
+                &[Modifier::Shift, Modifier::Ctrl],
+                &[Modifier::Ctrl, Modifier::Alt]
+            )),
+            codes(vec![
+                event(KeyCode::KEY_LEFTSHIFT, false),
+                event(KeyCode::KEY_LEFTALT, true),
+            ])
+        );
+        assert!(UinputOutput::mod_events(&[Modifier::Cmd], &[Modifier::Cmd]).is_empty());
+    }
+}
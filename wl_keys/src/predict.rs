@@ -0,0 +1,316 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::config;
+
+/// How much a committed word counts for,
+/// relative to the counts in the dictionary.
+const LEARN_WEIGHT: u64 = 10;
+
+/// Whether the char can be part of a word
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '\''
+}
+
+/// The word that's being typed, just before the cursor.
+///
+/// This is empty if the cursor isn't right after a word.
+#[must_use]
+pub fn partial_word(text: &str, cursor: usize) -> &str {
+    let Some(before) = text.get(..cursor) else {
+        return "";
+    };
+
+    let start = before
+        .char_indices()
+        .rev()
+        .take_while(|&(_, c)| is_word_char(c))
+        .last()
+        .map_or(before.len(), |(i, _)| i);
+
+    &before[start..]
+}
+
+/// Where `wl_keys` keeps its data, `$XDG_DATA_HOME/wl_keys`
+fn data_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .map(|dir| dir.join("wl_keys"))
+}
+
+/// The lowercase words in a dictionary and their counts.
+///
+/// Each line is a word, optionally followed by whitespace and its count,
+/// and lines starting with '#' are ignored.
+fn parse_counts(contents: &str) -> impl Iterator<Item = (String, u64)> + '_ {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let word = parts.next()?;
+            let count = parts.next().and_then(|c| c.parse().ok()).unwrap_or(1);
+            Some((word.to_lowercase(), count))
+        })
+}
+
+/// Rewrite the learned file with each word and its count,
+/// so it only grows with new words
+fn save(path: &Path, learned: &HashMap<String, u64>) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut words = learned.iter().collect::<Vec<_>>();
+    words.sort();
+    let mut contents = String::new();
+    for (word, count) in words {
+        let _ = writeln!(contents, "{word} {count}");
+    }
+
+    // Written beside it then moved over it, so it's never left half written
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+/// Suggests words from a word frequency dictionary,
+/// and learns from the words that get committed.
+#[derive(Default)]
+pub struct Predictor {
+    /// Lowercase words and how often they're used
+    words: HashMap<String, u64>,
+    /// Where learned words are saved, so they're kept between sessions
+    learned: Option<PathBuf>,
+    /// How many times each word has been learned, which is what's saved
+    learned_counts: HashMap<String, u64>,
+}
+
+impl Predictor {
+    /// Load the dictionary from `path`,
+    /// or from `$XDG_DATA_HOME/wl_keys/words.txt` if that exists,
+    /// and the words learned before from `$XDG_DATA_HOME/wl_keys/learned`.
+    ///
+    /// Without a dictionary it only knows the words it learns.
+    pub fn find(path: Option<&Path>) -> Result<Self> {
+        let data_dir = data_dir();
+
+        let dictionary = path.map(Path::to_path_buf).or_else(|| {
+            data_dir
+                .as_ref()
+                .map(|dir| dir.join(config::DICTIONARY_FILE))
+                .filter(|path| path.is_file())
+        });
+
+        Self::load(
+            dictionary.as_deref(),
+            data_dir.map(|dir| dir.join(config::LEARNED_FILE)),
+        )
+    }
+
+    /// Load a dictionary file, and the words learned in earlier sessions.
+    ///
+    /// Each line of the dictionary is a word, optionally followed by
+    /// whitespace and its count, and lines starting with '#' are ignored.
+    ///
+    /// The learned file is in the same format, with how many times each word
+    /// was learned, and it's rewritten as words are learned.
+    /// It's fine for it not to exist yet.
+    pub fn load(dictionary: Option<&Path>, learned: Option<PathBuf>) -> Result<Self> {
+        let mut predictor = Self::default();
+
+        if let Some(path) = dictionary {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read dictionary {}", path.display()))?;
+            for (word, count) in parse_counts(&contents) {
+                *predictor.words.entry(word).or_default() += count;
+            }
+        }
+
+        if let Some(path) = &learned {
+            match std::fs::read_to_string(path) {
+                Ok(contents) => {
+                    for (word, count) in parse_counts(&contents) {
+                        *predictor.learned_counts.entry(word.clone()).or_default() += count;
+                        *predictor.words.entry(word).or_default() += count * LEARN_WEIGHT;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                // Losing the learned words shouldn't stop suggestions working
+                Err(err) => eprintln!(
+                    "Warning: failed to read learned words {}: {err}",
+                    path.display()
+                ),
+            }
+        }
+
+        predictor.learned = learned;
+
+        Ok(predictor)
+    }
+
+    /// Count the words in some committed text,
+    /// and save them so they're known next session
+    pub fn learn(&mut self, text: &str) {
+        let mut learned = false;
+
+        for word in text.split(|c| !is_word_char(c)) {
+            let word = word.trim_matches('\'');
+
+            // Single letters aren't worth suggesting
+            if word.chars().nth(1).is_none() {
+                continue;
+            }
+
+            let word = word.to_lowercase();
+            *self.learned_counts.entry(word.clone()).or_default() += 1;
+            *self.words.entry(word).or_default() += LEARN_WEIGHT;
+            learned = true;
+        }
+
+        if let (Some(path), true) = (&self.learned, learned) {
+            if let Err(err) = save(path, &self.learned_counts) {
+                eprintln!(
+                    "Failed to save learned words to {}: {err:#}",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    /// Suggest the most used words that start with `partial`,
+    /// keeping its capitalisation.
+    #[must_use]
+    pub fn suggest(&self, partial: &str, limit: usize) -> Vec<String> {
+        if partial.is_empty() {
+            return Vec::new();
+        }
+
+        let prefix = partial.to_lowercase();
+
+        let mut matches = self
+            .words
+            .iter()
+            .filter(|(word, _)| word.starts_with(&prefix) && **word != prefix)
+            .collect::<Vec<_>>();
+        // Most used first, then alphabetically so it's stable
+        matches.sort_by(|(a_word, a_count), (b_word, b_count)| {
+            b_count.cmp(a_count).then_with(|| a_word.cmp(b_word))
+        });
+
+        let capitalise = partial.chars().next().is_some_and(char::is_uppercase);
+
+        matches
+            .into_iter()
+            .take(limit)
+            .map(|(word, _)| {
+                if capitalise {
+                    let mut chars = word.chars();
+                    chars
+                        .next()
+                        .map(|first| first.to_uppercase().chain(chars).collect())
+                        .unwrap_or_default()
+                } else {
+                    word.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_word_before_the_cursor() {
+        assert_eq!(partial_word("hello wor", 9), "wor");
+        // Only the part before the cursor
+        assert_eq!(partial_word("hello world", 8), "wo");
+        assert_eq!(partial_word("don't", 5), "don't");
+    }
+
+    #[test]
+    fn partial_word_at_a_boundary() {
+        assert_eq!(partial_word("hello ", 6), "");
+        assert_eq!(partial_word("hello", 0), "");
+        assert_eq!(partial_word("hello,", 6), "");
+        // Past the end of the text
+        assert_eq!(partial_word("hello", 10), "");
+    }
+
+    #[test]
+    fn partial_word_multibyte() {
+        assert_eq!(partial_word("un café", 8), "café");
+        assert_eq!(partial_word("日本語", 6), "日本");
+        // The cursor is a byte offset, in the middle of 'é'
+        assert_eq!(partial_word("un café", 7), "");
+    }
+
+    #[test]
+    fn suggests_the_most_used_first() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("wl_keys-words-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# a comment\nhelp 5\nhello 20\nhelmet\nHELLO 2\nworld 100\n",
+        )?;
+        let predictor = Predictor::load(Some(&path), None);
+        std::fs::remove_file(&path)?;
+        let predictor = predictor?;
+
+        assert_eq!(predictor.suggest("hel", 3), ["hello", "help", "helmet"]);
+        assert_eq!(predictor.suggest("hel", 1), ["hello"]);
+        // The capitalisation is kept
+        assert_eq!(predictor.suggest("Hel", 2), ["Hello", "Help"]);
+        // The word itself isn't suggested
+        assert_eq!(predictor.suggest("hello", 3), Vec::<String>::new());
+        assert_eq!(predictor.suggest("", 3), Vec::<String>::new());
+
+        Ok(())
+    }
+
+    #[test]
+    fn learned_words_are_counted() {
+        let mut predictor = Predictor::default();
+        predictor.learn("I'm 'here', I said hero");
+        predictor.learn("Here");
+
+        assert_eq!(predictor.words.get("here"), Some(&(LEARN_WEIGHT * 2)));
+        assert_eq!(predictor.words.get("i'm"), Some(&LEARN_WEIGHT));
+        // Single letters aren't learnt
+        assert_eq!(predictor.words.get("i"), None);
+        assert_eq!(predictor.suggest("he", 3), ["here", "hero"]);
+    }
+
+    #[test]
+    fn learned_words_are_kept_between_sessions() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("wl_keys-learned-{}", std::process::id()));
+        let path = dir.join(config::LEARNED_FILE);
+
+        let mut predictor = Predictor::load(None, Some(path.clone()))?;
+        predictor.learn("wayland keyboard");
+        predictor.learn("wayland");
+
+        let contents = std::fs::read_to_string(&path);
+        let predictor = Predictor::load(None, Some(path));
+        std::fs::remove_dir_all(&dir)?;
+        let predictor = predictor?;
+
+        // A line per word, rather than per time it was learned
+        assert_eq!(contents?, "keyboard 1\nwayland 2\n");
+        assert_eq!(predictor.words.get("wayland"), Some(&(LEARN_WEIGHT * 2)));
+        assert_eq!(predictor.suggest("k", 3), ["keyboard"]);
+
+        Ok(())
+    }
+}
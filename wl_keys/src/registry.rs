@@ -0,0 +1,184 @@
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, HashMap};
+use wayland_client::backend::protocol::Interface;
+
+use crate::proto;
+
+/// The oldest version of each interface that `wl_keys` can use,
+/// anything not listed works from v1.
+const MIN_VERSIONS: &[(&str, u32)] = &[
+    // Seats are chosen by the name event added in v2
+    ("wl_seat", 2),
+];
+
+/// Pick the version to bind, the newest that both sides support.
+///
+/// This fails if the compositor's version is older than `wl_keys` needs.
+pub fn negotiate(interface: &Interface, server_version: u32) -> Result<u32> {
+    let min_version = MIN_VERSIONS
+        .iter()
+        .find(|(name, _)| *name == interface.name)
+        .map_or(1, |&(_, version)| version);
+
+    if server_version < min_version {
+        bail!(
+            "{} v{server_version} is too old, at least v{min_version} is needed",
+            interface.name
+        );
+    }
+
+    Ok(server_version.min(interface.version))
+}
+
+/// A global object advertised by the compositor
+pub struct Global {
+    /// The interface it implements, like `wl_seat`
+    pub interface: String,
+    /// The version of the implemented protocol
+    pub version: u32,
+    /// The version it was bound with, if it's used
+    pub bound_version: Option<u32>,
+}
+
+/// All of the globals the compositor has advertised,
+/// kept up to date as they're added and removed.
+#[derive(Default)]
+pub struct Registry {
+    /// Keyed by the global's name,
+    /// which is really more of an id, but I'll keep consistency with wayland
+    globals: HashMap<u32, Global>,
+}
+
+impl Registry {
+    /// Track a new global
+    pub fn add(&mut self, name: u32, interface: String, version: u32) {
+        self.globals.insert(
+            name,
+            Global {
+                interface,
+                version,
+                bound_version: None,
+            },
+        );
+    }
+
+    /// Record the version a global was bound with
+    pub fn set_bound(&mut self, name: u32, version: u32) {
+        if let Some(global) = self.globals.get_mut(&name) {
+            global.bound_version = Some(version);
+        }
+    }
+
+    /// Stop tracking a global, returning it if it was known
+    pub fn remove(&mut self, name: u32) -> Option<Global> {
+        self.globals.remove(&name)
+    }
+
+    /// The first global implementing `interface`, and its name.
+    ///
+    /// The lowest name is used so the choice doesn't change between runs.
+    #[must_use]
+    pub fn find(&self, interface: &str) -> Option<(u32, &Global)> {
+        self.globals
+            .iter()
+            .filter(|(_, global)| global.interface == interface)
+            .min_by_key(|(&name, _)| name)
+            .map(|(&name, global)| (name, global))
+    }
+
+    /// Each interface with its highest version, the version it was bound with,
+    /// and the globals implementing it, sorted by interface
+    #[must_use]
+    pub fn protocols(&self) -> Vec<proto::Protocol> {
+        let mut protocols = BTreeMap::<&str, proto::Protocol>::new();
+
+        for (&name, global) in &self.globals {
+            let protocol = protocols
+                .entry(&global.interface)
+                .or_insert_with(|| proto::Protocol {
+                    interface: global.interface.clone(),
+                    ..proto::Protocol::default()
+                });
+            protocol.version = protocol.version.max(global.version);
+            protocol.bound_version = protocol
+                .bound_version
+                .max(global.bound_version.unwrap_or_default());
+            protocol.count += 1;
+            protocol.names.push(name);
+            protocol.used |= global.bound_version.is_some();
+        }
+
+        protocols
+            .into_values()
+            .map(|mut protocol| {
+                protocol.names.sort_unstable();
+                protocol
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wayland_client::{
+        protocol::{wl_compositor::WlCompositor, wl_seat::WlSeat},
+        Proxy,
+    };
+
+    #[test]
+    fn negotiates_the_newest_shared_version() -> Result<()> {
+        let seat = WlSeat::interface();
+
+        assert_eq!(negotiate(seat, 2)?, 2);
+        assert_eq!(negotiate(seat, seat.version)?, seat.version);
+        // Newer than wl_keys knows about
+        assert_eq!(negotiate(seat, seat.version + 1)?, seat.version);
+
+        Ok(())
+    }
+
+    #[test]
+    fn too_old_versions_fail() -> Result<()> {
+        let err = negotiate(WlSeat::interface(), 1)
+            .err()
+            .map(|err| err.to_string());
+        assert_eq!(
+            err.as_deref(),
+            Some("wl_seat v1 is too old, at least v2 is needed")
+        );
+
+        // Anything without a minimum works from v1
+        assert_eq!(negotiate(WlCompositor::interface(), 1)?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tracks_globals() {
+        let mut registry = Registry::default();
+        registry.add(5, "wl_seat".to_string(), 7);
+        registry.add(3, "wl_seat".to_string(), 5);
+        registry.add(4, "wl_compositor".to_string(), 6);
+        registry.set_bound(3, 5);
+
+        // The lowest name is found first
+        assert_eq!(registry.find("wl_seat").map(|(name, _)| name), Some(3));
+
+        let protocols = registry.protocols();
+        assert_eq!(
+            protocols
+                .iter()
+                .map(|protocol| (protocol.interface.as_str(), protocol.count, protocol.used))
+                .collect::<Vec<_>>(),
+            [("wl_compositor", 1, false), ("wl_seat", 2, true)]
+        );
+        assert_eq!(protocols[1].names, [3, 5]);
+        assert_eq!(protocols[1].version, 7);
+        assert_eq!(protocols[1].bound_version, 5);
+
+        assert!(registry.remove(3).is_some());
+        assert!(registry.remove(3).is_none());
+        assert_eq!(registry.find("wl_seat").map(|(name, _)| name), Some(5));
+    }
+}
@@ -0,0 +1,589 @@
+use anyhow::{anyhow, Result};
+use std::{
+    os::{fd::AsFd, unix::net::UnixStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+use wayland_client::Connection;
+use wayland_protocols_misc::{
+    zwp_input_method_v2::server::{
+        zwp_input_method_manager_v2::{self, ZwpInputMethodManagerV2},
+        zwp_input_method_v2::{self, ZwpInputMethodV2},
+    },
+    zwp_virtual_keyboard_v1::server::{
+        zwp_virtual_keyboard_manager_v1::{self, ZwpVirtualKeyboardManagerV1},
+        zwp_virtual_keyboard_v1::{self, ZwpVirtualKeyboardV1},
+    },
+};
+use wayland_server::{
+    backend::{ClientId, GlobalId},
+    protocol::{
+        wl_keyboard::{self, WlKeyboard},
+        wl_seat::{self, WlSeat},
+    },
+    Client, DataInit, Dispatch, Display, DisplayHandle, GlobalDispatch, New, Resource,
+};
+
+use crate::{
+    args::StartArgs,
+    keyboard::Keyboard,
+    keymap::{self, Keymap, KeymapSpec},
+    output::OutputKind,
+    ui,
+};
+
+/// The name of the seat the test compositor starts with
+pub const SEAT_NAME: &str = "seat0";
+
+/// A request the test compositor received from `wl_keys`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// The virtual keyboard was given a keymap, with its symbols
+    Keymap(String),
+    /// The virtual keyboard pressed or released a key
+    Key {
+        /// The evdev keycode
+        key: u32,
+        /// False if it was released
+        pressed: bool,
+    },
+    /// The virtual keyboard set its modifiers
+    Modifiers {
+        /// The held modifiers
+        depressed: u32,
+        /// The modifiers applied to the next key
+        latched: u32,
+        /// The modifiers like caps lock
+        locked: u32,
+    },
+    /// The input method committed text
+    CommitString(String),
+    /// The input method set the preedit text
+    SetPreedit {
+        /// The uncommitted text
+        text: String,
+        /// The byte offset the cursor starts at
+        cursor_begin: i32,
+        /// The byte offset the cursor ends at
+        cursor_end: i32,
+    },
+    /// The input method deleted text around the cursor
+    DeleteSurroundingText {
+        /// Bytes before the cursor
+        before_length: u32,
+        /// Bytes after the cursor
+        after_length: u32,
+    },
+    /// The input method applied its pending requests
+    Commit(u32),
+}
+
+/// The compositor's side of everything,
+/// only touched with the lock held.
+struct Server {
+    /// Everything `wl_keys` has asked for, in order,
+    /// with the name of the seat it was sent on
+    requests: Vec<(String, Request)>,
+    /// Sent to each `wl_keyboard` on the seat
+    keymap: Keymap,
+    /// The input methods that haven't been destroyed
+    input_methods: Vec<ZwpInputMethodV2>,
+    /// The virtual keyboards that haven't been destroyed
+    virtual_kbds: Vec<ZwpVirtualKeyboardV1>,
+}
+
+/// The user data of a virtual keyboard
+struct VirtualKeyboard {
+    /// Whether it has a keymap yet
+    has_keymap: AtomicBool,
+    /// The name of the seat it was created on
+    seat: String,
+}
+
+struct Shared {
+    display: Display<Server>,
+    server: Server,
+    /// The advertised seats, by name
+    seats: Vec<(String, GlobalId)>,
+}
+
+/// A compositor with just the globals `wl_keys` needs,
+/// running in-process so the keyboard can be tested without a real one.
+///
+/// It advertises a seat with a keyboard, the virtual keyboard manager,
+/// and the input method manager, and records what's sent to them.
+/// More seats can be added and removed with `add_seat` and `remove_seat`.
+pub struct TestCompositor {
+    shared: Arc<Mutex<Shared>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestCompositor {
+    /// Start the compositor on its own thread,
+    /// so it can answer while a client blocks on a roundtrip
+    pub fn new() -> Result<Self> {
+        let display = Display::<Server>::new()?;
+        let handle = display.handle();
+        let seat = handle.create_global::<Server, WlSeat, String>(7, SEAT_NAME.to_string());
+        handle.create_global::<Server, ZwpVirtualKeyboardManagerV1, ()>(1, ());
+        handle.create_global::<Server, ZwpInputMethodManagerV2, ()>(1, ());
+
+        let server = Server {
+            requests: Vec::new(),
+            keymap: Keymap::from_text(&KeymapSpec::Default.text()?)?,
+            input_methods: Vec::new(),
+            virtual_kbds: Vec::new(),
+        };
+
+        let shared = Arc::new(Mutex::new(Shared {
+            display,
+            server,
+            seats: vec![(SEAT_NAME.to_string(), seat)],
+        }));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread = std::thread::spawn({
+            let shared = shared.clone();
+            let running = running.clone();
+            move || {
+                while running.load(Ordering::Relaxed) {
+                    if let Ok(mut shared) = shared.lock() {
+                        let Shared {
+                            display, server, ..
+                        } = &mut *shared;
+                        // A client that broke the protocol is disconnected,
+                        // which the test will see as an error
+                        let _ = display.dispatch_clients(server);
+                        let _ = display.flush_clients();
+                    }
+
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+        });
+
+        Ok(Self {
+            shared,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    fn shared(&self) -> Result<MutexGuard<'_, Shared>> {
+        self.shared
+            .lock()
+            // Have to do this because the PoisonError is not Sync
+            .map_err(|_| anyhow!("Mutex poisoned"))
+    }
+
+    /// Connect a new client
+    pub fn connect(&self) -> Result<Connection> {
+        let (server_socket, client_socket) = UnixStream::pair()?;
+
+        self.shared()?
+            .display
+            .handle()
+            .insert_client(server_socket, Arc::new(()))?;
+
+        Ok(Connection::from_socket(client_socket)?)
+    }
+
+    /// Connect a `Keyboard`, the way the daemon does on startup
+    pub fn keyboard(&self) -> Result<Keyboard> {
+        Keyboard::from_connection(self.connect()?)
+    }
+
+    /// Take everything received since the last call.
+    ///
+    /// Do a roundtrip first, so the compositor has handled all that was sent.
+    pub fn take_requests(&self) -> Result<Vec<Request>> {
+        Ok(self
+            .take_seat_requests()?
+            .into_iter()
+            .map(|(_, request)| request)
+            .collect())
+    }
+
+    /// Like `take_requests`, with the name of the seat each was sent on
+    pub fn take_seat_requests(&self) -> Result<Vec<(String, Request)>> {
+        Ok(std::mem::take(&mut self.shared()?.server.requests))
+    }
+
+    /// Advertise another seat with a keyboard
+    pub fn add_seat(&self, name: &str) -> Result<()> {
+        let mut shared = self.shared()?;
+
+        let id = shared
+            .display
+            .handle()
+            .create_global::<Server, WlSeat, String>(7, name.to_string());
+        shared.seats.push((name.to_string(), id));
+        shared.display.flush_clients()?;
+        Ok(())
+    }
+
+    /// Remove a seat's global, like when its devices are unplugged
+    pub fn remove_seat(&self, name: &str) -> Result<()> {
+        let mut shared = self.shared()?;
+
+        let index = shared
+            .seats
+            .iter()
+            .position(|(seat, _)| seat == name)
+            .ok_or_else(|| anyhow!("No seat {name}"))?;
+        let (_, id) = shared.seats.remove(index);
+        shared.display.handle().remove_global::<Server>(id);
+        shared.display.flush_clients()?;
+        Ok(())
+    }
+
+    /// The seats of the virtual keyboards that haven't been destroyed
+    pub fn virtual_keyboard_seats(&self) -> Result<Vec<String>> {
+        Ok(self
+            .shared()?
+            .server
+            .virtual_kbds
+            .iter()
+            .filter_map(|virtual_kbd| virtual_kbd.data::<VirtualKeyboard>())
+            .map(|data| data.seat.clone())
+            .collect())
+    }
+
+    /// The seats of the input methods that haven't been destroyed
+    pub fn input_method_seats(&self) -> Result<Vec<String>> {
+        Ok(self
+            .shared()?
+            .server
+            .input_methods
+            .iter()
+            .filter_map(|input_method| input_method.data::<String>().cloned())
+            .collect())
+    }
+
+    /// Send events to every input method, followed by a `done` to apply them
+    pub fn send_input(&self, events: impl Fn(&ZwpInputMethodV2)) -> Result<()> {
+        let mut shared = self.shared()?;
+
+        for input_method in &shared.server.input_methods {
+            events(input_method);
+            input_method.done();
+        }
+
+        shared.display.flush_clients()?;
+        Ok(())
+    }
+
+    /// Focus a text field
+    pub fn activate(&self) -> Result<()> {
+        self.send_input(ZwpInputMethodV2::activate)
+    }
+
+    /// Unfocus the text field
+    pub fn deactivate(&self) -> Result<()> {
+        self.send_input(ZwpInputMethodV2::deactivate)
+    }
+
+    /// Give the input method to another IME,
+    /// making every input method unavailable
+    pub fn take_input_method(&self) -> Result<()> {
+        let mut shared = self.shared()?;
+
+        for input_method in &shared.server.input_methods {
+            input_method.unavailable();
+        }
+
+        shared.display.flush_clients()?;
+        Ok(())
+    }
+}
+
+impl Drop for TestCompositor {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Let the ui be opened and closed in tests,
+/// using `true` instead of eww so nothing is shown
+pub fn init_ui() {
+    let args = StartArgs {
+        eww_config: Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../eww")),
+        eww_bin: Some(PathBuf::from("true")),
+        dictionary: None,
+        hide_delay: 0,
+        input_method_retry: None,
+        seat: Vec::new(),
+        fallback_keymap: KeymapSpec::Default,
+        keymap: None,
+        output: OutputKind::Wayland,
+    };
+
+    // Every test calls this, but it can only be set once
+    let _ = ui::init(&args);
+}
+
+impl GlobalDispatch<WlSeat, String> for Server {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<WlSeat>,
+        name: &String,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let seat = data_init.init(resource, name.clone());
+        seat.capabilities(wl_seat::Capability::Keyboard);
+        if seat.version() >= 2 {
+            seat.name(name.clone());
+        }
+    }
+}
+
+impl Dispatch<WlSeat, String> for Server {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _seat: &WlSeat,
+        request: wl_seat::Request,
+        _data: &String,
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        // wl_keys only asks for the keyboard, to copy its keymap
+        if let wl_seat::Request::GetKeyboard { id } = request {
+            let keyboard = data_init.init(id, ());
+            keyboard.keymap(
+                wl_keyboard::KeymapFormat::XkbV1,
+                state.keymap.fd.as_fd(),
+                state.keymap.size,
+            );
+        }
+    }
+}
+
+impl Dispatch<WlKeyboard, ()> for Server {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _keyboard: &WlKeyboard,
+        _request: wl_keyboard::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl GlobalDispatch<ZwpVirtualKeyboardManagerV1, ()> for Server {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ZwpVirtualKeyboardManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<ZwpVirtualKeyboardManagerV1, ()> for Server {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _manager: &ZwpVirtualKeyboardManagerV1,
+        request: zwp_virtual_keyboard_manager_v1::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let zwp_virtual_keyboard_manager_v1::Request::CreateVirtualKeyboard { seat, id } =
+            request
+        {
+            let virtual_kbd = data_init.init(
+                id,
+                VirtualKeyboard {
+                    has_keymap: AtomicBool::new(false),
+                    seat: seat.data::<String>().cloned().unwrap_or_default(),
+                },
+            );
+            state.virtual_kbds.push(virtual_kbd);
+        }
+    }
+}
+
+impl Dispatch<ZwpVirtualKeyboardV1, VirtualKeyboard> for Server {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        virtual_kbd: &ZwpVirtualKeyboardV1,
+        request: zwp_virtual_keyboard_v1::Request,
+        data: &VirtualKeyboard,
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        use zwp_virtual_keyboard_v1::Request as VirtualRequest;
+
+        // Like a real compositor, keys can't be sent before the keymap
+        let no_keymap = || {
+            virtual_kbd.post_error(
+                zwp_virtual_keyboard_v1::Error::NoKeymap,
+                "No keymap was set",
+            );
+        };
+
+        match request {
+            VirtualRequest::Keymap { fd, size, .. } => {
+                let contents = Keymap { fd, size }.contents().unwrap_or_default();
+                data.has_keymap.store(true, Ordering::Relaxed);
+                state.requests.push((
+                    data.seat.clone(),
+                    Request::Keymap(keymap::symbols(&contents)),
+                ));
+            }
+            VirtualRequest::Key {
+                key,
+                state: key_state,
+                ..
+            } => {
+                if !data.has_keymap.load(Ordering::Relaxed) {
+                    return no_keymap();
+                }
+
+                state.requests.push((
+                    data.seat.clone(),
+                    Request::Key {
+                        key,
+                        pressed: key_state == u32::from(wl_keyboard::KeyState::Pressed),
+                    },
+                ));
+            }
+            VirtualRequest::Modifiers {
+                mods_depressed,
+                mods_latched,
+                mods_locked,
+                ..
+            } => {
+                if !data.has_keymap.load(Ordering::Relaxed) {
+                    return no_keymap();
+                }
+
+                state.requests.push((
+                    data.seat.clone(),
+                    Request::Modifiers {
+                        depressed: mods_depressed,
+                        latched: mods_latched,
+                        locked: mods_locked,
+                    },
+                ));
+            }
+            _ => (),
+        }
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        virtual_kbd: &ZwpVirtualKeyboardV1,
+        _data: &VirtualKeyboard,
+    ) {
+        state
+            .virtual_kbds
+            .retain(|other| other.id() != virtual_kbd.id());
+    }
+}
+
+impl GlobalDispatch<ZwpInputMethodManagerV2, ()> for Server {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ZwpInputMethodManagerV2>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<ZwpInputMethodManagerV2, ()> for Server {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _manager: &ZwpInputMethodManagerV2,
+        request: zwp_input_method_manager_v2::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let zwp_input_method_manager_v2::Request::GetInputMethod { seat, input_method } = request
+        {
+            // Tagged with its seat's name
+            let input_method = data_init.init(
+                input_method,
+                seat.data::<String>().cloned().unwrap_or_default(),
+            );
+            state.input_methods.push(input_method);
+        }
+    }
+}
+
+impl Dispatch<ZwpInputMethodV2, String> for Server {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _input_method: &ZwpInputMethodV2,
+        request: zwp_input_method_v2::Request,
+        seat: &String,
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        use zwp_input_method_v2::Request as ImRequest;
+
+        let request = match request {
+            ImRequest::CommitString { text } => Request::CommitString(text),
+            ImRequest::SetPreeditString {
+                text,
+                cursor_begin,
+                cursor_end,
+            } => Request::SetPreedit {
+                text,
+                cursor_begin,
+                cursor_end,
+            },
+            ImRequest::DeleteSurroundingText {
+                before_length,
+                after_length,
+            } => Request::DeleteSurroundingText {
+                before_length,
+                after_length,
+            },
+            ImRequest::Commit { serial } => Request::Commit(serial),
+            // wl_keys doesn't use the popup or keyboard grab
+            _ => return,
+        };
+
+        state.requests.push((seat.clone(), request));
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        input_method: &ZwpInputMethodV2,
+        _data: &String,
+    ) {
+        state
+            .input_methods
+            .retain(|other| other.id() != input_method.id());
+    }
+}
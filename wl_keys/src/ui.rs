@@ -1,65 +1,386 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use std::{
+    ffi::OsStr,
+    fmt::Write,
+    io::{ErrorKind, Read},
     path::{Path, PathBuf},
-    process::{Command, Stdio},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{mpsc, OnceLock},
+    time::{Duration, Instant},
 };
+use tokio::sync::oneshot;
 
-fn spawn_silent(cmd: impl Into<String>) -> Result<()> {
-    let cmd: String = cmd.into();
-    let mut parts = cmd.split(' ');
+use crate::{args::StartArgs, config, content::ContentType};
 
-    Command::new(parts.next().context("Empty string given")?)
-        .args(parts)
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()?;
+/// Where to find eww and its config.
+#[derive(Debug)]
+struct EwwPaths {
+    bin: PathBuf,
+    config: PathBuf,
+    /// This executable, for the config to run
+    wl_keys: PathBuf,
+}
+
+/// Set by `init` when the daemon starts.
+///
+/// If eww couldn't be found this holds why,
+/// so the daemon still runs and the error is given when the ui is used.
+static PATHS: OnceLock<Result<EwwPaths, String>> = OnceLock::new();
+
+/// The error for when the eww binary doesn't exist
+fn not_found(bin: &Path) -> anyhow::Error {
+    anyhow!(
+        "eww not found at {}, is it installed? Set it with --eww-bin or ${}",
+        bin.display(),
+        config::EWW_BIN_ENV
+    )
+}
 
-    Ok(())
+/// Builds up an eww command, keeping each argument intact.
+struct Eww {
+    cmd: Command,
+}
+
+impl Eww {
+    /// Start an eww command using the found config
+    fn new() -> Result<Self> {
+        let paths = match PATHS.get().context("The ui hasn't been initialised")? {
+            Ok(paths) => paths,
+            Err(err) => bail!("{err}"),
+        };
+
+        let mut cmd = Command::new(&paths.bin);
+        cmd.arg("--config")
+            .arg(&paths.config)
+            .env(config::BIN_ENV, &paths.wl_keys)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+
+        Ok(Self { cmd })
+    }
+
+    /// Add arguments to the command
+    fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.cmd.args(args);
+        self
+    }
+
+    /// For commands that can start the eww daemon.
+    ///
+    /// The daemon keeps the stderr it was given open,
+    /// so it isn't captured or reading it would never finish.
+    /// It's given the daemon's stderr instead, so its errors are still logged.
+    fn daemonises(mut self) -> Self {
+        self.cmd.stderr(Stdio::inherit());
+        self
+    }
+
+    /// Run the command and wait for it to exit.
+    ///
+    /// Anything eww writes to stderr is forwarded to the daemon's stderr,
+    /// and a non-zero exit is turned into an error.
+    fn run(mut self) -> Result<()> {
+        let mut child = match self.cmd.spawn() {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(not_found(Path::new(self.cmd.get_program())));
+            }
+            res => res.context("Failed to spawn eww")?,
+        };
+
+        let status = wait(&mut child)?;
+
+        let mut stderr = String::new();
+        if let Some(mut pipe) = child.stderr.take() {
+            pipe.read_to_string(&mut stderr)
+                .context("Failed to read eww's stderr")?;
+        }
+
+        for line in stderr.lines() {
+            eprintln!("eww: {line}");
+        }
+
+        if !status.success() {
+            // Empty if it went straight to the daemon's stderr
+            let stderr = stderr.trim();
+            if stderr.is_empty() {
+                bail!("{:?} failed ({status})", self.cmd);
+            }
+            bail!("{:?} failed ({status}): {stderr}", self.cmd);
+        }
+
+        Ok(())
+    }
+}
+
+/// Wait for eww to exit, reaping it.
+///
+/// If it takes longer than `config::EWW_TIMEOUT` it's killed,
+/// so a stuck eww can't stop the ui from being used again.
+fn wait(child: &mut Child) -> Result<ExitStatus> {
+    let deadline = Instant::now() + config::EWW_TIMEOUT;
+
+    loop {
+        if let Some(status) = child.try_wait().context("Failed to wait for eww")? {
+            return Ok(status);
+        }
+
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!(
+                "eww didn't exit after {}s, so it was killed",
+                config::EWW_TIMEOUT.as_secs()
+            );
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// An eww command for the ui worker to run
+struct Job {
+    args: Vec<String>,
+    /// Whether the command can start the eww daemon
+    daemonises: bool,
+    reply: oneshot::Sender<Result<()>>,
+}
+
+impl Job {
+    fn run(self) {
+        let res = Eww::new().and_then(|eww| {
+            let eww = eww.args(&self.args);
+            if self.daemonises {
+                eww.daemonises().run()
+            } else {
+                eww.run()
+            }
+        });
+
+        // No one is waiting for it, like when auto mode hides the ui
+        if let Err(Err(err)) = self.reply.send(res) {
+            eprintln!("Failed to update the ui: {err:#}");
+        }
+    }
+}
+
+/// The ui worker runs eww commands in order on its own thread,
+/// so a slow or stuck eww never holds up the wayland thread.
+fn worker() -> &'static mpsc::Sender<Job> {
+    static WORKER: OnceLock<mpsc::Sender<Job>> = OnceLock::new();
+
+    WORKER.get_or_init(|| {
+        let (jobs_tx, jobs_rx) = mpsc::channel::<Job>();
+
+        // If this fails the receiver is dropped, so every job fails to send
+        let _ = std::thread::Builder::new()
+            .name("ui".to_string())
+            .spawn(move || jobs_rx.into_iter().for_each(Job::run));
+
+        jobs_tx
+    })
+}
+
+/// An eww command that's been given to the ui worker.
+///
+/// If it's dropped without waiting, any error is logged instead.
+pub struct Pending(oneshot::Receiver<Result<()>>);
+
+impl Pending {
+    /// Already finished with `res`, for when eww isn't needed
+    #[must_use]
+    pub fn ready(res: Result<()>) -> Self {
+        let (tx, rx) = oneshot::channel();
+        let _ = tx.send(res);
+        Self(rx)
+    }
+
+    /// Wait for eww to finish
+    pub async fn wait(self) -> Result<()> {
+        self.0.await.context("The ui worker stopped")?
+    }
+}
+
+/// Give an eww command to the ui worker
+fn send(args: Vec<String>, daemonises: bool) -> Pending {
+    let (reply, rx) = oneshot::channel();
+
+    if let Err(mpsc::SendError(job)) = worker().send(Job {
+        args,
+        daemonises,
+        reply,
+    }) {
+        let _ = job.reply.send(Err(anyhow!("The ui worker stopped")));
+    }
+
+    Pending(rx)
 }
 
 fn is_config_here(path: impl AsRef<Path>) -> Result<bool> {
-    Ok(
-        std::fs::read_dir(path)?
-            .any(|entry| entry.map(|e| e.file_name() == "eww").unwrap_or(false)),
-    )
+    Ok(std::fs::read_dir(path)?.any(|entry| entry.is_ok_and(|e| e.file_name() == "eww")))
+}
+
+/// Search the parents of `path` for an `eww` dir.
+///
+/// This is what finds the config when running from the repo,
+/// or when the eww dir is installed next to `bin`.
+fn find_config_path(mut path: PathBuf) -> Option<PathBuf> {
+    loop {
+        if is_config_here(&path).unwrap_or(false) {
+            return Some(path.join("eww"));
+        }
+
+        if !path.pop() {
+            return None;
+        }
+    }
+}
+
+/// A valid config dir has the main yuck file in it.
+fn is_config(path: &Path) -> bool {
+    path.join("eww.yuck").is_file()
+}
+
+fn env_path(var: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
 }
 
-fn find_config_path(mut path: PathBuf) -> Result<PathBuf> {
-    if is_config_here(&path)? {
-        return Ok(path);
+/// Find the eww config.
+///
+/// If `--eww-config` or `$WL_KEYS_EWW_CONFIG` are given, that path must be valid.
+/// Otherwise these are searched in order:
+/// - `$XDG_CONFIG_HOME/wl_keys/eww`
+/// - `/usr/share/wl_keys/eww`
+/// - an `eww` dir in a parent of the executable
+fn config_path(arg: Option<PathBuf>) -> Result<PathBuf> {
+    let explicit = arg
+        .map(|path| (path, "--eww-config".to_string()))
+        .or_else(|| {
+            env_path(config::EWW_CONFIG_ENV)
+                .map(|path| (path, format!("${}", config::EWW_CONFIG_ENV)))
+        });
+
+    if let Some((path, source)) = explicit {
+        if is_config(&path) {
+            return Ok(path);
+        }
+
+        bail!(
+            "{} (from {source}) is not an eww config, it has no eww.yuck",
+            path.display()
+        );
     }
 
-    if !path.pop() {
-        return Err(anyhow!("Can't find eww config"));
+    let xdg_config = env_path("XDG_CONFIG_HOME")
+        .or_else(|| env_path("HOME").map(|home| home.join(".config")))
+        .map(|path| path.join("wl_keys").join("eww"));
+    let exe_relative = std::env::current_exe()
+        .ok()
+        .and_then(|mut exe| exe.pop().then_some(exe))
+        .and_then(find_config_path);
+
+    let candidates = [
+        xdg_config,
+        Some(PathBuf::from(config::SYSTEM_EWW_CONFIG)),
+        exe_relative,
+    ];
+
+    let mut tried = String::new();
+    for path in candidates.into_iter().flatten() {
+        if is_config(&path) {
+            return Ok(path);
+        }
+
+        let _ = write!(tried, "\n  {}", path.display());
     }
 
-    find_config_path(path)
+    bail!(
+        "Can't find the eww config, set it with --eww-config or ${}, tried:{tried}",
+        config::EWW_CONFIG_ENV
+    )
 }
 
-fn config_path() -> Result<String> {
-    let mut exe = std::env::current_exe()?;
-    exe.pop();
-    let path_buf = find_config_path(exe)?;
-    let path = path_buf
-        .to_str()
-        .ok_or_else(|| anyhow!("Exe path was invalid unicode"))?;
-    Ok(format!("{path}/eww"))
+/// Find the eww binary from `--eww-bin`, `$WL_KEYS_EWW_BIN`, or `$PATH`.
+fn bin_path(arg: Option<PathBuf>) -> PathBuf {
+    arg.or_else(|| env_path(config::EWW_BIN_ENV))
+        .unwrap_or_else(|| PathBuf::from("eww"))
 }
 
-/// Open the UI
-pub fn open() -> Result<()> {
-    spawn_silent(format!("eww --config {} open keyboard", config_path()?))
+/// Find eww and its config, this must be called before opening or closing the UI.
+///
+/// If the config can't be found the error is returned to be logged,
+/// and given again each time the UI is opened or closed.
+pub fn init(args: &StartArgs) -> Result<()> {
+    let paths = config_path(args.eww_config.clone())
+        .map(|config| EwwPaths {
+            bin: bin_path(args.eww_bin.clone()),
+            config,
+            wl_keys: std::env::current_exe().unwrap_or_else(|_| PathBuf::from("wl_keys")),
+        })
+        .map_err(|err| format!("{err:#}"));
+    let res = paths.as_ref().map(|_| ()).map_err(|err| anyhow!("{err}"));
+
+    PATHS
+        .set(paths)
+        .map_err(|_| anyhow!("The ui was already initialised"))?;
+
+    res
+}
+
+/// Check that eww can be run and its config found, without starting it.
+///
+/// Returns the config dir and eww's version.
+pub fn check(args: &StartArgs) -> Result<(PathBuf, String)> {
+    let config = config_path(args.eww_config.clone())?;
+    let bin = bin_path(args.eww_bin.clone());
+
+    let output = match Command::new(&bin).arg("--version").output() {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Err(not_found(&bin)),
+        res => res.context("Failed to run eww")?,
+    };
+
+    if !output.status.success() {
+        bail!(
+            "{} --version failed ({}): {}",
+            bin.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok((
+        config,
+        String::from_utf8_lossy(&output.stdout).trim().to_string(),
+    ))
+}
+
+/// Open the UI, this starts the eww daemon if it isn't running
+#[must_use]
+pub fn open() -> Pending {
+    send(vec!["open".to_string(), "keyboard".to_string()], true)
 }
 
 /// Close the UI
-pub fn close() -> Result<()> {
-    spawn_silent(format!("eww --config {} close keyboard", config_path()?))
+#[must_use]
+pub fn close() -> Pending {
+    send(vec!["close".to_string(), "keyboard".to_string()], false)
 }
 
-/// Toggle the UI
-pub fn toggle() -> Result<()> {
-    spawn_silent(format!(
-        "eww --config {} open --toggle keyboard",
-        config_path()?
-    ))
+/// Switch the UI to suit the focused text field
+#[must_use]
+pub fn update_content(content_type: &ContentType) -> Pending {
+    send(
+        vec![
+            "update".to_string(),
+            format!("layer={}", content_type.layer().as_str()),
+            format!("suggestions={}", content_type.suggestions()),
+        ],
+        false,
+    )
 }